@@ -0,0 +1,261 @@
+use std::io;
+use std::io::Read as _;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use flate2::read::GzDecoder;
+use zip::read::ZipArchive;
+
+use crate::build;
+
+/// Total bytes extracted from one submitted file, counting nested archives
+/// and their contents, so a zip bomb can't exhaust memory.
+const MAX_SIZE: u64 = 64 << 20;
+
+/// Archives nested deeper than this are rejected.
+const MAX_DEPTH: usize = 4;
+
+/// A file extracted from an archive that a student submitted.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// File name without any directories, e.g. `parse.y`.
+    pub name: String,
+
+    /// Full path within the archive (and any archives nested inside it).
+    pub path: String,
+
+    pub contents: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    fn new(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Kind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Kind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Kind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Extract every file in `contents`, if `name` looks like a zip, tar, or
+/// tar.gz archive. Directory structure is flattened away, and archives
+/// nested inside are extracted in turn. Object files and prebuilt binaries
+/// are left out, as the old scripts deleted them, so `make` rebuilds them
+/// from the student's sources.
+///
+/// Returns `Ok(None)` if `name` isn't an archive, or an error if the
+/// archive is larger than [`MAX_SIZE`] or nested deeper than [`MAX_DEPTH`].
+pub fn extract(name: &str, contents: &[u8]) -> anyhow::Result<Option<Vec<Entry>>> {
+    let mut budget = MAX_SIZE;
+    extract_nested(name, contents, 0, &mut budget)
+}
+
+fn extract_nested(
+    name: &str,
+    contents: &[u8],
+    depth: usize,
+    budget: &mut u64,
+) -> anyhow::Result<Option<Vec<Entry>>> {
+    let kind = match Kind::new(name) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+
+    if depth > MAX_DEPTH {
+        return Err(anyhow!(
+            "Archives nested more than {} deep are not supported",
+            MAX_DEPTH
+        ));
+    }
+
+    let mut files = Vec::new();
+
+    match kind {
+        Kind::Zip => {
+            let mut archive = ZipArchive::new(io::Cursor::new(contents))?;
+            for index in 0..archive.len() {
+                let mut file = archive.by_index(index)?;
+                if file.is_dir() {
+                    continue;
+                }
+
+                let path = String::from(file.name());
+                let contents = read(&mut file, &path, budget)?;
+                files.push((path, contents));
+            }
+        }
+        Kind::Tar => read_tar(contents, &mut files, budget)?,
+        Kind::TarGz => read_tar(GzDecoder::new(contents), &mut files, budget)?,
+    }
+
+    let mut entries = Vec::new();
+
+    for (path, contents) in files {
+        let name = match path.rsplit('/').next() {
+            // Skip macOS resource forks and other hidden files
+            Some(name) if !name.is_empty() && !name.starts_with('.') => name,
+            _ => continue,
+        };
+
+        if path.split('/').any(|directory| directory == "__MACOSX") {
+            continue;
+        }
+
+        if is_built(name, &contents) {
+            continue;
+        }
+
+        match extract_nested(name, &contents, depth + 1, budget)
+            .with_context(|| anyhow!("Could not extract nested archive {}", path))?
+        {
+            Some(nested) => entries.extend(nested.into_iter().map(|entry| Entry {
+                path: format!("{}/{}", path, entry.path),
+                ..entry
+            })),
+            None => entries.push(Entry {
+                name: String::from(name),
+                path,
+                contents,
+            }),
+        }
+    }
+
+    Ok(Some(entries))
+}
+
+fn read_tar<R: io::Read>(
+    reader: R,
+    files: &mut Vec<(String, Vec<u8>)>,
+    budget: &mut u64,
+) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let contents = read(&mut entry, &path, budget)?;
+        files.push((path, contents));
+    }
+
+    Ok(())
+}
+
+/// Read all of `reader`, taking its size out of `budget`. Sizes in archive
+/// headers aren't trusted, since they can lie.
+fn read<R: io::Read>(reader: R, path: &str, budget: &mut u64) -> anyhow::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    reader
+        .take(*budget + 1)
+        .read_to_end(&mut contents)
+        .with_context(|| anyhow!("Could not read {}", path))?;
+
+    match budget.checked_sub(contents.len() as u64) {
+        Some(left) => *budget = left,
+        None => {
+            return Err(anyhow!(
+                "Archive is larger than {} MiB when extracted",
+                MAX_SIZE >> 20
+            ))
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Whether `name` is an object file, a generated parser or lexer, or a
+/// binary, which `make` might otherwise consider up to date.
+fn is_built(name: &str, contents: &[u8]) -> bool {
+    name.ends_with(".o") || build::GENERATED.contains(&name) || contents.starts_with(b"\x7fELF")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::extract;
+    use super::MAX_DEPTH;
+    use super::MAX_SIZE;
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn names(name: &str, contents: &[u8]) -> Vec<String> {
+        extract(name, contents)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect()
+    }
+
+    #[test]
+    fn nested() {
+        let inner = tar(&[("parse.y", b"%%")]);
+        let outer = tar(&[("p3/inner.tar", &inner), ("lexan.l", b"%%")]);
+        assert_eq!(
+            names("outer.tar", &outer),
+            ["p3/inner.tar/parse.y", "lexan.l"]
+        );
+    }
+
+    #[test]
+    fn skips_build_products() {
+        let archive = tar(&[
+            ("parse.y", b"%%"),
+            ("parse.o", b"\x7fELF"),
+            ("y.tab.c", b"int yyparse;"),
+            ("parser", b"\x7fELF\x02\x01\x01"),
+        ]);
+        assert_eq!(names("p3.tar", &archive), ["parse.y"]);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut archive = tar(&[("parse.y", b"%%")]);
+        for _ in 0..MAX_DEPTH {
+            archive = tar(&[("nested.tar", &archive)]);
+        }
+        assert_eq!(names("p3.tar", &archive).len(), 1);
+
+        let archive = tar(&[("nested.tar", &archive)]);
+        assert!(extract("p3.tar", &archive).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        let zeros = vec![0; MAX_SIZE as usize + 1];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&tar(&[("zeros", &zeros)])).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        assert!(extract("bomb.tar.gz", &bomb).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Data directives that make up literal-pool entries.
+const DATA: [&str; 8] = [
+    ".long", ".quad", ".word", ".byte", ".double", ".float", ".string", ".ascii",
+];
+
+/// A data directive and its arguments, e.g. `.long 0`.
+type Data<'a> = (&'a str, &'a [String]);
+
+/// One statement of x86-64 AT&T assembly, with comments and insignificant
+/// whitespace removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Statement {
+    /// `name:`
+    Label(String),
+
+    /// `.name args`, e.g. `.long 0`.
+    Directive(String, Vec<String>),
+
+    /// `mnemonic operands`, e.g. `movl %eax,-32(%rbp)`.
+    Instruction(String, Vec<String>),
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (name, args) = match self {
+            Statement::Label(name) => return write!(fmt, "{}:", name),
+            Statement::Directive(name, args) | Statement::Instruction(name, args) => (name, args),
+        };
+
+        match args.is_empty() {
+            true => write!(fmt, "{}", name),
+            false => write!(fmt, "{} {}", name, args.join(",")),
+        }
+    }
+}
+
+/// Normalize generated `code` for comparison, one statement per line:
+/// local labels are renamed in order of first use, literal-pool references
+/// are replaced by the value they point to (defined in `code` or `data`),
+/// and comments, `.ident`, and whitespace inside operands are dropped.
+pub(crate) fn normalize(code: &str, data: &str) -> Vec<String> {
+    let code = parse(code);
+    let pool = pool(code.iter().chain(&parse(data)));
+
+    // Drop literals defined among the code, so only their uses are compared
+    let mut literal = false;
+    let code = code.into_iter().filter(|statement| {
+        literal = match statement {
+            Statement::Label(name) => pool.contains_key(name),
+            Statement::Directive(name, _) => literal && DATA.contains(&name.as_str()),
+            Statement::Instruction(..) => false,
+        };
+        !literal && !matches!(statement, Statement::Directive(name, _) if name == ".align")
+    });
+
+    let mut labels = BTreeMap::new();
+    let mut rename = |name: &str| -> String {
+        if let Some(value) = pool.get(name) {
+            return format!("<{}>", value);
+        }
+
+        let next = labels.len();
+        let index = *labels.entry(name.to_owned()).or_insert(next);
+        format!(".L{}", index)
+    };
+
+    code.map(|statement| match statement {
+        Statement::Label(name) if is_local(&name) => Statement::Label(rename(&name)),
+        Statement::Instruction(mnemonic, operands) => Statement::Instruction(
+            mnemonic,
+            operands
+                .iter()
+                .map(|operand| symbols(operand, &mut rename))
+                .collect(),
+        ),
+        statement => statement,
+    })
+    .map(|statement| statement.to_string())
+    .collect()
+}
+
+/// Tokenize each line of `code` into statements.
+fn parse(code: &str) -> Vec<Statement> {
+    let mut statements = Vec::new();
+
+    for line in code.lines() {
+        let mut line = uncomment(line).trim();
+
+        // `name:` may share a line with the statement after it
+        while let Some((name, rest)) = line.split_once(':') {
+            if name.is_empty() || !name.chars().all(is_symbol) {
+                break;
+            }
+            statements.push(Statement::Label(name.to_owned()));
+            line = rest.trim_start();
+        }
+
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = split(args);
+
+        match name {
+            "" | ".ident" => (),
+            _ if name.starts_with('.') => {
+                statements.push(Statement::Directive(name.to_owned(), args))
+            }
+            _ => statements.push(Statement::Instruction(name.to_owned(), args)),
+        }
+    }
+
+    statements
+}
+
+/// Literal-pool entries in `statements`: labels followed only by data
+/// directives, and the values those directives define.
+fn pool<'a, I>(statements: I) -> BTreeMap<String, String>
+where
+    I: IntoIterator<Item = &'a Statement>,
+{
+    let mut pool = BTreeMap::new();
+    let mut current: Option<(&str, Vec<Data>)> = None;
+
+    for statement in statements.into_iter().map(Some).chain(Some(None)) {
+        let data = match statement {
+            Some(Statement::Directive(name, args)) if DATA.contains(&name.as_str()) => {
+                if let Some((_, data)) = &mut current {
+                    data.push((name.as_str(), args.as_slice()));
+                }
+                continue;
+            }
+            Some(Statement::Directive(name, _)) if name == ".align" => continue,
+            _ => current.take(),
+        };
+
+        if let Some((label, data)) = data.filter(|(_, data)| !data.is_empty()) {
+            pool.insert(label.to_owned(), value(&data));
+        }
+
+        if let Some(Statement::Label(label)) = statement {
+            current = Some((label, Vec::new()));
+        }
+    }
+
+    pool
+}
+
+/// Human-readable value of a literal-pool entry, e.g. `4.5` for the two
+/// `.long` halves of a double, or `"i = "` for a `.string`.
+fn value(data: &[Data]) -> String {
+    if let [(".long", [low]), (".long", [high])] = data {
+        if let (Ok(low), Ok(high)) = (low.parse::<i64>(), high.parse::<i64>()) {
+            let bits = (high as u32 as u64) << 32 | low as u32 as u64;
+            return format!("{:?}", f64::from_bits(bits));
+        }
+    }
+
+    data.iter()
+        .map(|(name, args)| match *name {
+            ".string" | ".ascii" => args.join(","),
+            _ => format!("{} {}", name, args.join(",")),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Apply `rename` to each local label in `operand`.
+fn symbols<F: FnMut(&str) -> String>(operand: &str, rename: &mut F) -> String {
+    let mut normalized = String::new();
+    let mut rest = operand;
+
+    while let Some(start) = rest.find(".L") {
+        let preceded = rest[..start].chars().next_back().is_some_and(is_symbol);
+        let end = rest[start..]
+            .find(|char: char| !is_symbol(char))
+            .map_or(rest.len(), |end| start + end);
+
+        normalized.push_str(&rest[..start]);
+        match preceded {
+            true => normalized.push_str(&rest[start..end]),
+            false => normalized.push_str(&rename(&rest[start..end])),
+        }
+        rest = &rest[end..];
+    }
+
+    normalized.push_str(rest);
+    normalized
+}
+
+/// `line` without a trailing `#` comment, leaving `#` inside strings alone.
+fn uncomment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, char) in line.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+/// Split `args` on commas outside parentheses and strings, removing
+/// whitespace outside strings from each.
+fn split(args: &str) -> Vec<String> {
+    let mut split = vec![String::new()];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for char in args.chars() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if quoted => (),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(String::new());
+                continue;
+            }
+            _ if char.is_whitespace() => continue,
+            _ => (),
+        }
+        split.last_mut().unwrap().push(char);
+    }
+
+    split.retain(|arg| !arg.is_empty());
+    split
+}
+
+fn is_local(name: &str) -> bool {
+    name.starts_with(".L")
+}
+
+fn is_symbol(char: char) -> bool {
+    char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '@')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+    use super::parse;
+    use super::pool;
+    use super::split;
+    use super::symbols;
+    use super::value;
+    use super::Statement;
+
+    /// Code and literal data split the way `p6::extract` splits a sample.
+    fn sample(body: &str, literals: &str) -> (String, String) {
+        let code = format!(
+            "{}# ----------------------- begin Epilogue code ---------------------------
+\tmovq\t%r9, %rbx        # restore %rbx (callee-saved) from %r9
+        leave
+        ret
+        .cfi_endproc
+.LFE0:
+        .size   graph1, .-graph1
+",
+            body
+        );
+        let data = format!(
+            "# ----------------- end Epilogue; Literal data follows ------------------
+        .section        .rodata
+{}
+        .ident  \"CS 375 Compiler - Spring 2022\"",
+            literals
+        );
+        (code, data)
+    }
+
+    fn normalized(body: &str, literals: &str) -> Vec<String> {
+        let (code, data) = sample(body, literals);
+        normalize(&code, &data)
+    }
+
+    const LOOP: &str = "\
+.L0:
+\tmovq\t-32(%rbp),%rax     \t#  ptr -> %rax
+\tcmpq\t%rcx,%rax           \t#  compare %rax - %rcx
+\tjne\t.L2 \t\t\t#  jump if     !=
+\tjmp\t.L3 \t\t\t#  jump
+.L2:
+\tmovsd\t.LC4(%rip),%xmm0   \t#  4.500000 -> %xmm0
+\tleaq\t.LC5(%rip),%rdi
+\tjmp\t.L0 \t\t\t#  jump
+.L3:
+";
+
+    const LITERALS: &str = "\
+\t.align  8
+.LC4:
+\t.long\t0   \t#  4.500000
+\t.long\t1074921472
+.LC5:
+\t.string\t\"i = \"
+";
+
+    #[test]
+    fn renumbered_labels() {
+        let renumbered = LOOP
+            .replace(".L0", ".L7")
+            .replace(".L2", ".L0")
+            .replace(".L3", ".L1")
+            .replace(".LC4", ".LC2")
+            .replace(".LC5", ".LC1");
+        let literals = LITERALS.replace(".LC4", ".LC2").replace(".LC5", ".LC1");
+        assert_eq!(
+            normalized(&renumbered, &literals),
+            normalized(LOOP, LITERALS)
+        );
+
+        // Jumping to the wrong one of two labels is still a difference
+        let swapped = LOOP
+            .replace("jne\t.L2", "jne\t.L9")
+            .replace("jmp\t.L3", "jmp\t.L2")
+            .replace("jne\t.L9", "jne\t.L3");
+        assert_ne!(normalized(&swapped, LITERALS), normalized(LOOP, LITERALS));
+    }
+
+    #[test]
+    fn literal_pool() {
+        let reordered = "\
+.LC5:
+\t.string\t\"i = \"
+\t.align  8
+.LC4:
+\t.long\t0
+\t.long\t1074921472
+";
+        assert_eq!(normalized(LOOP, reordered), normalized(LOOP, LITERALS));
+
+        let normalized = normalized(LOOP, LITERALS);
+        assert!(normalized.contains(&String::from("movsd <4.5>(%rip),%xmm0")));
+        assert!(normalized.contains(&String::from("leaq <\"i = \">(%rip),%rdi")));
+        assert!(!normalized.iter().any(|line| line.contains(".long")));
+
+        // A different value is a difference, even under the same label
+        let changed = LITERALS.replace("1074921472", "1074790400");
+        assert_ne!(self::normalized(LOOP, &changed), normalized);
+    }
+
+    #[test]
+    fn ident() {
+        let (code, data) = sample(LOOP, LITERALS);
+        assert!(parse(&data).iter().all(
+            |statement| !matches!(statement, Statement::Directive(name, _) if name == ".ident")
+        ));
+        assert!(!normalize(&code, &data)
+            .iter()
+            .any(|line| line.contains("ident")));
+    }
+
+    #[test]
+    fn operand_whitespace() {
+        assert_eq!(
+            normalized("\tmovl\t%eax, -32(%rbp)\n", ""),
+            normalized("\tmovl\t%eax,-32(%rbp)     \t#  %eax -> i\n", "")
+        );
+        assert_eq!(split("%eax, -32(%rbp)"), ["%eax", "-32(%rbp)"]);
+        assert_eq!(split("0(%rax, %rcx, 8), %rdx"), ["0(%rax,%rcx,8)", "%rdx"]);
+
+        // Strings keep their commas and spaces
+        assert_eq!(split("\"a, b\""), ["\"a, b\""]);
+        assert_eq!(split(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pool_values() {
+        let (code, data) = sample(LOOP, LITERALS);
+        let statements = parse(&code)
+            .into_iter()
+            .chain(parse(&data))
+            .collect::<Vec<_>>();
+        let pool = pool(&statements);
+
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool[".LC4"], "4.5");
+        assert_eq!(pool[".LC5"], "\"i = \"");
+
+        let (low, high) = (vec![String::from("0")], vec![String::from("-1073610752")]);
+        assert_eq!(value(&[(".long", &low), (".long", &high)]), "-2.25");
+
+        let quad = vec![String::from("5")];
+        assert_eq!(value(&[(".quad", &quad)]), ".quad 5");
+    }
+
+    #[test]
+    fn local_symbols() {
+        let mut rename = |name: &str| format!("<{}>", name);
+        assert_eq!(symbols(".LC1(%rip)", &mut rename), "<.LC1>(%rip)");
+        assert_eq!(symbols(".L2", &mut rename), "<.L2>");
+        assert_eq!(symbols("-32(%rbp)", &mut rename), "-32(%rbp)");
+
+        // Only whole symbols that start with `.L`
+        assert_eq!(symbols("graph1.L2", &mut rename), "graph1.L2");
+    }
+}
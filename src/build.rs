@@ -0,0 +1,669 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Seek as _;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+
+use crate::report;
+use crate::report::PatchStatus;
+use crate::report::Report;
+use crate::sandbox::Sandbox;
+
+/// Name of the file in each workspace that `make` output is logged to.
+pub const LOG: &str = ".cs375-autograder.log";
+
+/// Error lines quoted from the log for each failure.
+const QUOTED: usize = 5;
+
+/// One way of building a project, e.g. `make parsec` when the student
+/// wrote a hand-written parser.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Recipe {
+    pub(crate) target: &'static str,
+
+    /// File produced by `make target`, relative to the workspace.
+    pub(crate) binary: &'static str,
+
+    /// Files that must all be present for this recipe to apply.
+    pub(crate) requires: &'static [&'static str],
+
+    /// Edits to make before building. If there are any, the workspace is
+    /// copied and built elsewhere, so the student's files are left alone.
+    pub(crate) patches: &'static [Patch],
+}
+
+/// An edit to one source file in a workspace.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Patch {
+    pub(crate) file: &'static str,
+    pub(crate) edit: Edit,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Edit {
+    /// Uncomment statements calling this function, from either a `/* */`
+    /// comment around the statement or a `//` comment before it.
+    Uncomment(&'static str),
+
+    /// Insert `text` before each statement calling `function`, unless it's
+    /// already there.
+    Before {
+        function: &'static str,
+        text: &'static str,
+    },
+
+    /// Comment out each statement starting with this identifier.
+    CommentOut(&'static str),
+}
+
+/// Build products that are left out of scratch copies, so `make` can't
+/// mistake them for being up to date with the patched sources.
+pub(crate) const GENERATED: &[&str] = &["y.tab.c", "y.tab.h", "lex.yy.c"];
+
+/// A binary built by a [`Recipe`].
+pub(crate) struct Binary {
+    pub(crate) path: PathBuf,
+
+    /// Scratch copy the binary was built in, if patched, which must outlive it.
+    _scratch: Option<Sandbox>,
+}
+
+/// Runs `make` in a workspace, logging its output.
+pub(crate) struct Builder<'a> {
+    sandbox: &'a Sandbox,
+    log: fs::File,
+}
+
+/// Why a workspace failed to build.
+#[derive(Clone, Debug)]
+pub(crate) enum Failure {
+    /// `make` has no rule for a file, which usually means it wasn't submitted.
+    MissingSource(String),
+
+    /// Files needed by each recipe but missing, so nothing was built.
+    NoRecipe(Vec<String>),
+
+    /// Bison or flex rejected a grammar or scanner specification.
+    Generator(Vec<String>),
+
+    Compiler(Vec<String>),
+    Linker(Vec<String>),
+
+    /// Anything else, e.g. `make` couldn't be executed.
+    Other(Vec<String>),
+}
+
+impl<'a> Builder<'a> {
+    /// Start a new log, replacing any from an earlier run.
+    pub(crate) fn new(sandbox: &'a Sandbox) -> anyhow::Result<Self> {
+        let path = sandbox.workspace().join(LOG);
+        let log = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| anyhow!("Could not create build log {}", path.display()))?;
+        Ok(Builder { sandbox, log })
+    }
+
+    /// Build with the first of `recipes` whose files are all present, and
+    /// return the binary it produces. Explains the choice, and any patches
+    /// applied, in `report`.
+    pub(crate) fn build(
+        &mut self,
+        recipes: &[Recipe],
+        report: &mut Report,
+    ) -> Result<Binary, Failure> {
+        let present = |file: &&str| self.sandbox.workspace().join(file).exists();
+
+        let mut skipped = Vec::new();
+        let recipe = recipes.iter().find(|recipe| {
+            let missing = recipe
+                .requires
+                .iter()
+                .filter(|file| !present(file))
+                .copied()
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                skipped.push(format!(
+                    "`make {}` needs {}",
+                    recipe.target,
+                    missing.join(", ")
+                ));
+            }
+            missing.is_empty()
+        });
+
+        let recipe = match recipe {
+            Some(recipe) => recipe,
+            None => {
+                let failure = Failure::NoRecipe(skipped);
+                let _ = writeln!(self.log, "{}", failure);
+                return Err(failure);
+            }
+        };
+
+        let mut reason = format!("Built with `make {}`", recipe.target);
+        if !recipe.requires.is_empty() {
+            reason.push_str(&format!(", since {} present", and(recipe.requires)));
+        }
+        if !skipped.is_empty() {
+            reason.push_str(&format!(" ({})", skipped.join("; ")));
+        }
+        let _ = writeln!(self.log, "{}", reason);
+        report.build = Some(reason);
+
+        if recipe.patches.is_empty() {
+            self.make(self.sandbox, recipe.target)?;
+            return Ok(Binary {
+                path: self.sandbox.workspace().join(recipe.binary),
+                _scratch: None,
+            });
+        }
+
+        let scratch = self
+            .patch(recipe, report)
+            .map_err(|error| self.other(error))?;
+        self.make(&scratch, recipe.target)?;
+        Ok(Binary {
+            path: scratch.workspace().join(recipe.binary),
+            _scratch: Some(scratch),
+        })
+    }
+
+    /// Copy the workspace without old build products, and apply the
+    /// recipe's patches to the copy.
+    fn patch(&mut self, recipe: &Recipe, report: &mut Report) -> anyhow::Result<Sandbox> {
+        let scratch = self.sandbox.scratch(|name| {
+            name == LOG
+                || name == recipe.binary
+                || name.ends_with(".o")
+                || GENERATED.contains(&name)
+        })?;
+        writeln!(
+            self.log,
+            "Building in scratch copy {}",
+            scratch.workspace().display()
+        )?;
+
+        for patch in recipe.patches {
+            let path = scratch.workspace().join(patch.file);
+            let source = match fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(error).with_context(|| anyhow!("Could not read {}", patch.file))
+                }
+            };
+
+            let patched = patch.edit.apply(&source);
+            let changed = patched != source;
+            if changed {
+                fs::write(&path, &patched)
+                    .with_context(|| anyhow!("Could not patch {}", patch.file))?;
+            }
+
+            let status = match (patch.edit.done(&patched), changed) {
+                (true, true) => PatchStatus::Applied,
+                (true, false) => PatchStatus::Unneeded,
+                (false, _) => PatchStatus::Failed,
+            };
+            match status {
+                PatchStatus::Applied => writeln!(self.log, "Patched to {}", patch)?,
+                PatchStatus::Unneeded => {
+                    writeln!(self.log, "Already done, not patched: {}", patch)?
+                }
+                PatchStatus::Failed => writeln!(self.log, "Could not patch to {}", patch)?,
+            }
+
+            report.patches.push(report::Patched {
+                edit: patch.to_string(),
+                status,
+            });
+        }
+
+        Ok(scratch)
+    }
+
+    fn make(&mut self, sandbox: &Sandbox, target: &str) -> Result<(), Failure> {
+        self.run(sandbox, target)
+            .map_err(|error| self.other(error))?
+    }
+
+    /// Log an error that isn't from the build itself.
+    fn other(&mut self, error: anyhow::Error) -> Failure {
+        // Best effort, since the failure is reported either way
+        let _ = writeln!(self.log, "{:#}", error);
+        Failure::Other(vec![format!("{:#}", error)])
+    }
+
+    /// Run `make target` with both output streams appended to the log, and
+    /// classify its output if it fails.
+    fn run(&mut self, sandbox: &Sandbox, target: &str) -> anyhow::Result<Result<(), Failure>> {
+        writeln!(self.log, "$ make {}", target)?;
+        let start = self.log.stream_position()?;
+
+        let status = sandbox
+            .command("make")
+            .arg(target)
+            .stdin(Stdio::null())
+            .stdout(self.log.try_clone()?)
+            .stderr(self.log.try_clone()?)
+            .status()
+            .context("Could not execute `make`")?;
+
+        let end = self.log.seek(io::SeekFrom::End(0))?;
+        if status.success() {
+            return Ok(Ok(()));
+        }
+
+        let mut output = vec![0; (end - start) as usize];
+        self.log.seek(io::SeekFrom::Start(start))?;
+        io::Read::read_exact(&mut self.log, &mut output)?;
+        self.log.seek(io::SeekFrom::End(0))?;
+
+        let output = String::from_utf8_lossy(&output);
+        Ok(Err(Failure::classify(&output).unwrap_or_else(|| {
+            let mut lines = last(&output);
+            lines.push(format!("`make {}` {}", target, status));
+            Failure::Other(lines)
+        })))
+    }
+}
+
+impl Edit {
+    fn apply(&self, source: &str) -> String {
+        match *self {
+            Edit::Uncomment(function) => {
+                uncomment_lines(&uncomment_blocks(source, function), function)
+            }
+            Edit::Before { function, text } => insert_before(source, function, text),
+            Edit::CommentOut(identifier) => comment_out(source, identifier),
+        }
+    }
+
+    /// Whether `source` does what the edit is meant to, patched or not: calls
+    /// the function, has `text` before each call, or has no statements
+    /// starting with the identifier.
+    fn done(&self, source: &str) -> bool {
+        let calls = |function| {
+            statements(source, function)
+                .into_iter()
+                .filter(move |&start| is_call(&source[start..], function))
+        };
+
+        match *self {
+            Edit::Uncomment(function) => calls(function).next().is_some(),
+            Edit::Before { function, text } => {
+                calls(function).all(|start| source[..start].trim_end().ends_with(text.trim()))
+            }
+            Edit::CommentOut(identifier) => statements(source, identifier).is_empty(),
+        }
+    }
+}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.edit {
+            Edit::Uncomment(function) => {
+                write!(fmt, "uncomment the call to `{}` in {}", function, self.file)
+            }
+            Edit::Before { function, text } => write!(
+                fmt,
+                "insert `{}` before `{}` in {}",
+                text.trim(),
+                function,
+                self.file
+            ),
+            Edit::CommentOut(identifier) => {
+                write!(fmt, "comment out `{}` in {}", identifier, self.file)
+            }
+        }
+    }
+}
+
+fn is_identifier(char: char) -> bool {
+    char.is_ascii_alphanumeric() || char == '_'
+}
+
+/// Whether `code` starts with a call to `function`.
+fn is_call(code: &str, function: &str) -> bool {
+    code.strip_prefix(function)
+        .is_some_and(|rest| rest.trim_start().starts_with('('))
+}
+
+/// `/* gencode(...); */` to `gencode(...);`.
+fn uncomment_blocks(source: &str, function: &str) -> String {
+    let mut patched = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find("/*") {
+        let end = match rest[start + 2..].find("*/") {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+
+        let comment = rest[start + 2..end].trim();
+        patched.push_str(&rest[..start]);
+        if is_call(comment, function) && comment.ends_with(';') {
+            patched.push_str(comment);
+        } else {
+            patched.push_str(&rest[start..end + 2]);
+        }
+        rest = &rest[end + 2..];
+    }
+
+    patched.push_str(rest);
+    patched
+}
+
+/// `// gencode(...); // comment` to `gencode(...);`.
+fn uncomment_lines(source: &str, function: &str) -> String {
+    source
+        .split_inclusive('\n')
+        .map(|line| {
+            let code = line.trim_start();
+            let indent = &line[..line.len() - code.len()];
+            let call = code
+                .strip_prefix("//")
+                .map(|comment| comment.trim_start_matches('/').trim_start())
+                .filter(|comment| is_call(comment, function))
+                .and_then(|comment| Some(&comment[..=comment.rfind(';')?]));
+
+            match call {
+                Some(call) => format!("{}{}{}", indent, call, &line[line.trim_end().len()..]),
+                None => String::from(line),
+            }
+        })
+        .collect()
+}
+
+/// Byte offsets of the statements in `source` that start with `word`: where
+/// it follows the end of another statement or block (and any comments after
+/// it), rather than e.g. a return type in a declaration, an `if`, or being
+/// inside a comment itself.
+fn statements(source: &str, word: &str) -> Vec<usize> {
+    source
+        .match_indices(word)
+        .map(|(start, _)| start)
+        .filter(|&start| {
+            let before = &source[..start];
+            let line = before.rsplit('\n').next().unwrap_or(before);
+            let commented = line.contains("//") || before.rfind("/*") > before.rfind("*/");
+
+            let before = uncomment_end(before);
+            !commented
+                && (before.is_empty() || before.ends_with([';', '{', '}']))
+                && !source[..start].ends_with(is_identifier)
+                && !source[start + word.len()..].starts_with(is_identifier)
+        })
+        .collect()
+}
+
+/// `code` without trailing whitespace and comments.
+fn uncomment_end(mut code: &str) -> &str {
+    loop {
+        code = code.trim_end();
+
+        let line = code.rfind('\n').map_or(0, |start| start + 1);
+        if code.ends_with("*/") {
+            match code[..code.len() - 2].rfind("/*") {
+                Some(start) => code = &code[..start],
+                None => return code,
+            }
+        } else if let Some(start) = code[line..].find("//") {
+            code = &code[..line + start];
+        } else {
+            return code;
+        }
+    }
+}
+
+/// Insert `text` before each statement calling `function`.
+fn insert_before(source: &str, function: &str, text: &str) -> String {
+    let mut patched = String::with_capacity(source.len());
+    let mut last = 0;
+
+    for start in statements(source, function) {
+        let inserted = source[..start].trim_end().ends_with(text.trim());
+        if is_call(&source[start..], function) && !inserted {
+            patched.push_str(&source[last..start]);
+            patched.push_str(text);
+            last = start;
+        }
+    }
+
+    patched.push_str(&source[last..]);
+    patched
+}
+
+/// `yydebug = 1;` to `/* yydebug = 1; */`.
+fn comment_out(source: &str, word: &str) -> String {
+    let mut patched = String::with_capacity(source.len());
+    let mut last = 0;
+
+    for start in statements(source, word) {
+        if let Some(end) = source[start..].find(';') {
+            patched.push_str(&source[last..start]);
+            patched.push_str(&format!("/* {} */", &source[start..=start + end]));
+            last = start + end + 1;
+        }
+    }
+
+    patched.push_str(&source[last..]);
+    patched
+}
+
+impl Failure {
+    /// Recognize the first (so most likely the root) cause in `make` output.
+    fn classify(output: &str) -> Option<Self> {
+        if let Some(name) = output.lines().find_map(missing) {
+            return Some(Failure::MissingSource(name));
+        }
+
+        // Bison and flex prefix errors with the `.y` or `.l` file name
+        let generator = quote(output, |line| {
+            line.split_once(':').is_some_and(|(file, _)| {
+                (file.ends_with(".y") || file.ends_with(".l")) && !file.contains(' ')
+            }) || line.starts_with("bison:")
+                || line.starts_with("flex:")
+                || ((line.contains("bison") || line.contains("flex")) && line.contains("not found"))
+        });
+        if !generator.is_empty() {
+            return Some(Failure::Generator(generator));
+        }
+
+        // Before compiler errors, since `collect2` reports link failures as `error:` too
+        let linker = quote(output, |line| {
+            line.contains("undefined reference to")
+                || line.contains("multiple definition of")
+                || line.contains("ld returned")
+                || line.starts_with("ld:")
+                || line.contains("/ld:")
+        });
+        if !linker.is_empty() {
+            return Some(Failure::Linker(linker));
+        }
+
+        let compiler = quote(output, |line| {
+            line.contains(": error:") || line.contains(": fatal error:")
+        });
+        if !compiler.is_empty() {
+            return Some(Failure::Compiler(compiler));
+        }
+
+        None
+    }
+}
+
+/// File that `make` or the compiler couldn't find, from e.g.
+/// `make: *** No rule to make target 'parse.y', needed by 'y.tab.c'.` or
+/// `lexan.c:2:10: fatal error: token.h: No such file or directory`.
+fn missing(line: &str) -> Option<String> {
+    if let Some((_, rest)) = line.split_once("No rule to make target ") {
+        let name = rest.trim_start_matches(['\'', '`']);
+        let end = name.find(['\'', '`'])?;
+        return Some(String::from(&name[..end]));
+    }
+
+    let rest = line.strip_suffix(": No such file or directory")?;
+    let (_, name) = rest.rsplit_once(": ")?;
+    Some(String::from(name.trim()))
+}
+
+/// `a`, `a is`, or `a and b are`.
+fn and(files: &[&str]) -> String {
+    match files {
+        [file] => format!("{} is", file),
+        files => format!("{} are", files.join(" and ")),
+    }
+}
+
+fn quote<F: Fn(&str) -> bool>(output: &str, matches: F) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| matches(line))
+        .take(QUOTED)
+        .map(String::from)
+        .collect()
+}
+
+fn last(output: &str) -> Vec<String> {
+    let lines = output.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(QUOTED)..]
+        .iter()
+        .map(|line| String::from(*line))
+        .collect()
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, lines) = match self {
+            Failure::MissingSource(name) => {
+                return write!(fmt, "missing source file `{}`", name);
+            }
+            Failure::NoRecipe(skipped) => {
+                return write!(fmt, "missing source file: {}", skipped.join("; "));
+            }
+            Failure::Generator(lines) => ("bison/flex error", lines),
+            Failure::Compiler(lines) => ("compiler error", lines),
+            Failure::Linker(lines) => ("linker error", lines),
+            Failure::Other(lines) => ("build error", lines),
+        };
+
+        write!(fmt, "{}", kind)?;
+        for line in lines {
+            write!(fmt, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::comment_out;
+    use super::insert_before;
+    use super::uncomment_lines;
+    use super::Edit;
+
+    /// `main()` from `rubrics/p3.md`.
+    const MAIN: &str = "int main(void)
+{
+    yydebug=1;
+    int res;
+    initsyms();
+    res = yyparse();
+    // printst();
+    printstlevel(1);
+    printf(\"yyparse result = %8d\\n\", res);
+    if (DEBUG & DB_PARSERES) dbugprinttok(parseresult);
+    ppexpr(parseresult);           /* Pretty-print the result tree */
+    /* uncomment following to call code generator. */
+    //gencode(parseresult, blockoffs[blocknumber], labelnumber);
+}
+";
+
+    const GENCODE: &str = "gencode(parseresult, blockoffs[blocknumber], labelnumber);";
+    const CANONICALIZE: &str = "exprCanonicalization(parseresult); ";
+
+    #[test]
+    fn uncomment() {
+        let patched = uncomment_lines(MAIN, "gencode");
+        assert!(patched.contains(&format!("\n    {}\n", GENCODE)));
+        assert!(patched.contains("// printst();"));
+        assert_eq!(uncomment_lines(&patched, "gencode"), patched);
+    }
+
+    #[test]
+    fn before() {
+        // Commented out, so not a statement yet
+        assert_eq!(insert_before(MAIN, "gencode", CANONICALIZE), MAIN);
+
+        let main = uncomment_lines(MAIN, "gencode");
+        let patched = insert_before(&main, "gencode", CANONICALIZE);
+        assert!(patched.contains(&format!(
+            "code generator. */\n    {}{}\n",
+            CANONICALIZE, GENCODE
+        )));
+        assert_eq!(insert_before(&patched, "gencode", CANONICALIZE), patched);
+
+        // After a line comment, and not inside an `if` without braces
+        let main = "    // comment\n    gencode(x);\n    if (x) gencode(x);\n";
+        assert_eq!(
+            insert_before(main, "gencode", CANONICALIZE),
+            format!(
+                "    // comment\n    {}gencode(x);\n    if (x) gencode(x);\n",
+                CANONICALIZE
+            )
+        );
+    }
+
+    #[test]
+    fn comment() {
+        let patched = comment_out(MAIN, "yydebug");
+        assert!(patched.contains("{\n    /* yydebug=1; */\n    int res;"));
+
+        let main = MAIN.replace("{\n", "{ /* debug */\n");
+        assert!(comment_out(&main, "yydebug").contains("/* yydebug=1; */"));
+
+        let main = MAIN.replace(
+            "printstlevel(1);",
+            "printstlevel(1); // trace\n    yydebug = 1;",
+        );
+        assert!(comment_out(&main, "yydebug").contains("// trace\n    /* yydebug = 1; */"));
+
+        // Already in a comment
+        let main = "    // yydebug = 1;\n    /* yydebug = 1; */\n";
+        assert_eq!(comment_out(main, "yydebug"), main);
+    }
+
+    #[test]
+    fn done() {
+        let gencode = Edit::Uncomment("gencode");
+        let canonicalize = Edit::Before {
+            function: "gencode",
+            text: CANONICALIZE,
+        };
+        let yydebug = Edit::CommentOut("yydebug");
+
+        assert!(!gencode.done(MAIN));
+        assert!(!yydebug.done(MAIN));
+
+        let patched = yydebug.apply(&canonicalize.apply(&gencode.apply(MAIN)));
+        assert!(gencode.done(&patched));
+        assert!(canonicalize.done(&patched));
+        assert!(yydebug.done(&patched));
+
+        // Nothing to do for a `main()` that already calls `gencode` and
+        // never sets `yydebug`
+        let main = uncomment_lines(MAIN, "gencode").replace("    yydebug=1;\n", "");
+        assert!(gencode.done(&main));
+        assert!(yydebug.done(&main));
+    }
+}
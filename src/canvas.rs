@@ -0,0 +1,204 @@
+use std::cmp::Ordering;
+
+/// A file from a Canvas submissions download, named like
+/// `lastfirst_LATE_123_456_parse-2.y`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Submission {
+    /// Canvas login name, e.g. `lastfirst`.
+    pub student: String,
+
+    /// Submitted after the deadline.
+    pub late: bool,
+
+    /// Canvas user id, if present.
+    pub user: Option<u64>,
+
+    /// Canvas submission (attachment) id, if present.
+    pub submission: Option<u64>,
+
+    /// Resubmission number from a `-2` or ` (2)` suffix, or 0 for none.
+    pub version: u32,
+
+    /// File name as the student uploaded it, e.g. `parse.y`.
+    pub name: String,
+}
+
+impl Submission {
+    /// Parse the file name of a submission, ignoring any leading directories.
+    ///
+    /// ```
+    /// # use cs375_autograder::canvas::Submission;
+    /// let submission = Submission::parse("lastfirst_LATE_123_456_parse-2.y").unwrap();
+    /// assert_eq!(submission.student, "lastfirst");
+    /// assert!(submission.late);
+    /// assert_eq!((submission.user, submission.submission), (Some(123), Some(456)));
+    /// assert_eq!(submission.version, 2);
+    /// assert_eq!(submission.name, "parse.y");
+    ///
+    /// let submission = Submission::parse("lastfirst_123_456_parse (1).y").unwrap();
+    /// assert!(!submission.late);
+    /// assert_eq!(submission.version, 1);
+    /// assert_eq!(submission.name, "parse.y");
+    ///
+    /// let submission = Submission::parse("lastfirst_123_lex_an-10.l").unwrap();
+    /// assert_eq!(submission.submission, None);
+    /// assert_eq!(submission.version, 10);
+    /// assert_eq!(submission.name, "lex_an.l");
+    ///
+    /// let submission = Submission::parse("lastfirst_123_456_makefile").unwrap();
+    /// assert_eq!((submission.version, submission.name.as_str()), (0, "makefile"));
+    ///
+    /// assert_eq!(Submission::parse("parse.y"), None);
+    /// ```
+    pub fn parse(path: &str) -> Option<Self> {
+        let file = path.rsplit('/').next().unwrap_or(path);
+        let mut fields = file.split('_').peekable();
+
+        let student = fields.next().filter(|student| !student.is_empty())?;
+        let late = fields.next_if_eq(&"LATE").is_some();
+
+        let mut ids = Vec::new();
+        while ids.len() < 2 {
+            match fields.next_if(|field| is_number(field)) {
+                Some(id) => ids.push(id.parse().ok()?),
+                None => break,
+            }
+        }
+
+        // The uploaded name may itself contain underscores
+        let name = fields.collect::<Vec<_>>().join("_");
+        if name.is_empty() {
+            return None;
+        }
+
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+            _ => (name.as_str(), None),
+        };
+        let (stem, version) = split_version(stem);
+
+        Some(Submission {
+            student: String::from(student),
+            late,
+            user: ids.first().copied(),
+            submission: ids.get(1).copied(),
+            version,
+            name: match extension {
+                Some(extension) => format!("{}.{}", stem, extension),
+                None => String::from(stem),
+            },
+        })
+    }
+
+    /// Order resubmissions of the same file, latest last.
+    pub fn cmp_version(&self, other: &Self) -> Ordering {
+        (self.version, self.submission).cmp(&(other.version, other.submission))
+    }
+}
+
+fn is_number(field: &str) -> bool {
+    !field.is_empty() && field.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Strip a Canvas (`parse-2`) or browser (`parse (2)`) version suffix.
+fn split_version(stem: &str) -> (&str, u32) {
+    let suffix = stem
+        .strip_suffix(')')
+        .and_then(|stem| stem.rsplit_once(" ("))
+        .or_else(|| stem.rsplit_once('-'));
+
+    match suffix {
+        Some((stem, version)) if !stem.is_empty() && is_number(version) => match version.parse() {
+            Ok(version) => (stem.trim_end(), version),
+            Err(_) => (stem, 0),
+        },
+        _ => (stem, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Submission;
+
+    fn parse(path: &str) -> Submission {
+        Submission::parse(path).unwrap()
+    }
+
+    #[test]
+    fn missing_ids() {
+        let submission = parse("lastfirst_parse.y");
+        assert_eq!((submission.user, submission.submission), (None, None));
+        assert_eq!(submission.name, "parse.y");
+
+        let submission = parse("lastfirst_LATE_parse.y");
+        assert!(submission.late);
+        assert_eq!((submission.user, submission.submission), (None, None));
+        assert_eq!(submission.name, "parse.y");
+
+        // Only the first two numeric fields are ids
+        let submission = parse("lastfirst_1_2_3_parse.y");
+        assert_eq!((submission.user, submission.submission), (Some(1), Some(2)));
+        assert_eq!(submission.name, "3_parse.y");
+    }
+
+    #[test]
+    fn missing_student_or_name() {
+        assert_eq!(Submission::parse(""), None);
+        assert_eq!(Submission::parse("_123_456_parse.y"), None);
+        assert_eq!(Submission::parse("lastfirst_123_456"), None);
+        assert_eq!(Submission::parse("lastfirst_LATE_123_456_"), None);
+    }
+
+    #[test]
+    fn late() {
+        let submission = parse("submissions/lastfirst_LATE_123_456_parse.y");
+        assert_eq!(submission.student, "lastfirst");
+        assert!(submission.late);
+        assert_eq!(submission.name, "parse.y");
+
+        // Only an exact `LATE` field after the student counts
+        let submission = parse("lastfirst_123_456_LATE.txt");
+        assert!(!submission.late);
+        assert_eq!(submission.name, "LATE.txt");
+    }
+
+    #[test]
+    fn resubmissions() {
+        assert_eq!(parse("lastfirst_123_456_parse-1.y").version, 1);
+        assert_eq!(parse("lastfirst_123_456_parse (3).y").version, 3);
+
+        let submission = parse("lastfirst_123_456_parse-1-2.y");
+        assert_eq!(
+            (submission.version, submission.name.as_str()),
+            (2, "parse-1.y")
+        );
+
+        // Hyphens that aren't followed by a number are part of the name
+        let submission = parse("lastfirst_123_456_lex-an.l");
+        assert_eq!(
+            (submission.version, submission.name.as_str()),
+            (0, "lex-an.l")
+        );
+
+        let submission = parse("lastfirst_123_456_-1.y");
+        assert_eq!((submission.version, submission.name.as_str()), (0, "-1.y"));
+    }
+
+    #[test]
+    fn non_ascii() {
+        let submission = parse("müllerjürgen_LATE_123_456_lexän-2.l");
+        assert_eq!(submission.student, "müllerjürgen");
+        assert!(submission.late);
+        assert_eq!(
+            (submission.version, submission.name.as_str()),
+            (2, "lexän.l")
+        );
+
+        // Only ASCII digits are version numbers
+        let submission = parse("lastfirst_123_456_parse-٢.y");
+        assert_eq!(
+            (submission.version, submission.name.as_str()),
+            (0, "parse-٢.y")
+        );
+    }
+}
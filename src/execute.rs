@@ -0,0 +1,348 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+
+use crate::report::Behavior;
+use crate::run;
+use crate::run::Exit;
+use crate::run::Options;
+use crate::sandbox::Sandbox;
+
+/// Stand-in for the course's `driver.c`, which isn't distributed with the
+/// autograder: calls the compiled program, and provides the runtime library
+/// that generated code calls. It only approximates the course driver: reals
+/// are printed with `%g`, and `iround` and `new` are guesses at what the
+/// course versions do. Student and sample code are always linked with the
+/// same driver, so only their behavior relative to each other matters, but
+/// pass the course driver with `--driver` to run programs exactly as the
+/// course does.
+const BUILTIN: &str = r#"#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+void graph1(void);
+
+void write(char str[]) { printf("%s", str); }
+void writeln(char str[]) { printf("%s\n", str); }
+void writei(int num) { printf("%d", num); }
+void writelni(int num) { printf("%d\n", num); }
+void writef(double num) { printf("%g", num); }
+void writelnf(double num) { printf("%g\n", num); }
+int iround(double num) { return (int) round(num); }
+void *new(int size) { return calloc(1, size); }
+
+int main(void) {
+  graph1();
+  fflush(stdout);
+  return 0;
+}
+"#;
+
+/// Prints the stack frame of `graph1` to stderr once the program body has run,
+/// so that programs that print nothing can still be compared by the values
+/// they leave in their variables. Words that point into the heap are printed
+/// relative to its start, since where the heap starts varies between runs.
+const FRAME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <unistd.h>
+
+static char *heap;
+
+__attribute__((constructor)) static void cs375_heap(void) { heap = sbrk(0); }
+
+void cs375_frame(char *rbp, long size) {
+  char *end = sbrk(0);
+  for (long offset = -size; offset < 0; offset += 8) {
+    uint64_t word = *(uint64_t *) (rbp + offset);
+    if ((char *) word >= heap && (char *) word < end)
+      fprintf(stderr, "%ld(%%rbp) heap+%ld\n", offset, (long) ((char *) word - heap));
+    else
+      fprintf(stderr, "%ld(%%rbp) 0x%016lx\n", offset, (unsigned long) word);
+  }
+}
+"#;
+
+/// Added at the start of the program body: clear the stack frame, so that
+/// variables read before they are set hold the same value in every run.
+const CLEAR: &str = "\tmovq\t%rsp, %rdi
+\tmovq\t%rbp, %rcx
+\tsubq\t%rsp, %rcx
+\txorl\t%eax, %eax
+\trep stosb
+";
+
+/// Where `genasm.c` starts its output, before any debugging output from the
+/// rest of the compiler.
+const START: &str = "# ---------------- Beginning of Generated Code";
+
+/// Where the code generated for the program body starts, after the entry
+/// code; samples start here.
+const BODY: &str = "begin Your code";
+
+/// Where the epilogue code starts, after the code for the program body.
+const EPILOGUE: &str = "begin Epilogue code";
+
+/// C source for `main` and the runtime library, linked with generated code.
+#[derive(Clone, Debug)]
+pub struct Driver {
+    source: String,
+}
+
+impl Driver {
+    pub fn builtin() -> Self {
+        Driver {
+            source: String::from(BUILTIN),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        fs::read_to_string(path)
+            .map(|source| Driver { source })
+            .with_context(|| anyhow!("Could not read driver {}", path.display()))
+    }
+}
+
+/// Scratch directory where generated code is assembled, linked, and run.
+pub(crate) struct Bench {
+    sandbox: Sandbox,
+}
+
+/// How a linked program behaved.
+#[derive(Debug, PartialEq)]
+struct Run {
+    stdout: String,
+
+    /// The stack frame of `graph1` after the program body, one word per line.
+    frame: String,
+
+    exit: Exit,
+}
+
+impl Bench {
+    /// Compile `driver` once, for every program run in `sandbox`.
+    pub(crate) fn new(sandbox: &Sandbox, driver: &Driver) -> anyhow::Result<Self> {
+        let bench = Bench {
+            sandbox: sandbox.scratch(|_| true)?,
+        };
+
+        let workspace = bench.sandbox.workspace();
+        fs::write(workspace.join("driver.c"), &driver.source)?;
+        fs::write(workspace.join("frame.c"), FRAME)?;
+
+        bench
+            .cc(&["-c", "driver.c", "-o", "driver.o"])
+            .context("Could not compile driver")?;
+        bench
+            .cc(&["-c", "frame.c", "-o", "frame.o"])
+            .context("Could not compile stack frame dump")?;
+        Ok(bench)
+    }
+
+    /// Run the student's compiler `output` and the `sample` it should match,
+    /// and compare what they print, the variables they leave in the stack
+    /// frame, and how they exit.
+    pub(crate) fn compare(&self, output: &str, sample: &str, options: &Options) -> Behavior {
+        let sample = assembly(sample).ok_or_else(|| anyhow!("no assembly code found"));
+        let size = sample.as_deref().map_or(0, frame);
+
+        let expected = sample
+            .and_then(|sample| instrument(&sample, size))
+            .and_then(|sample| self.run("sample", &sample, options));
+        let expected = match expected {
+            Ok(expected) => expected,
+            Err(error) => return Behavior::Error(format!("sample: {:#}", error)),
+        };
+
+        // Some samples only work with values in the stack frame that `CLEAR`
+        // zeroes, e.g. pointers, and there's nothing to compare a crash with
+        if expected.exit != Exit::Success {
+            return Behavior::Error(format!("sample {}", expected.exit));
+        }
+
+        let actual = assembly(output)
+            .ok_or_else(|| anyhow!("no assembly code found"))
+            .and_then(|output| instrument(&output, size))
+            .and_then(|output| self.run("student", &output, options));
+        let actual = match actual {
+            Ok(actual) => actual,
+            Err(error) => return Behavior::Different(format!("{:#}", error)),
+        };
+
+        if actual == expected {
+            return Behavior::Same;
+        }
+
+        if actual.exit != expected.exit {
+            return Behavior::Different(format!(
+                "program {}, but the sample {}",
+                actual.exit, expected.exit
+            ));
+        }
+
+        if actual.stdout != expected.stdout {
+            let (actual, expected) = actual
+                .stdout
+                .lines()
+                .chain(Some("(end of output)"))
+                .zip(expected.stdout.lines().chain(Some("(end of output)")))
+                .find(|(actual, expected)| actual != expected)
+                .unwrap_or(("(more output)", "(end of output)"));
+            return Behavior::Different(format!(
+                "program printed `{}` where the sample printed `{}`",
+                actual, expected
+            ));
+        }
+
+        let (actual, expected) = actual
+            .frame
+            .lines()
+            .zip(expected.frame.lines())
+            .find(|(actual, expected)| actual != expected)
+            .unwrap_or(("(nothing)", "(nothing)"));
+        let (offset, actual) = actual.split_once(' ').unwrap_or(("", actual));
+        let expected = expected.split_once(' ').map_or(expected, |(_, word)| word);
+        Behavior::Different(format!(
+            "program left {} in {} where the sample left {}",
+            actual, offset, expected
+        ))
+    }
+
+    /// Assemble `assembly`, link it with the driver, and run it.
+    fn run(&self, name: &str, assembly: &str, options: &Options) -> anyhow::Result<Run> {
+        let source = format!("{}.s", name);
+        fs::write(self.sandbox.workspace().join(&source), assembly)?;
+
+        self.cc(&["-o", name, &source, "driver.o", "frame.o", "-lm"])
+            .context("Could not assemble and link")?;
+
+        let program = self.sandbox.workspace().join(name);
+        let output = run::run(self.sandbox.command(program), &[], options)?;
+        Ok(Run {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            frame: String::from_utf8_lossy(&output.stderr).into_owned(),
+            exit: output.exit,
+        })
+    }
+
+    fn cc(&self, args: &[&str]) -> anyhow::Result<()> {
+        let output = self
+            .sandbox
+            .command("cc")
+            .args(args)
+            .output()
+            .context("Could not execute `cc`")?;
+
+        match output.status.success() {
+            true => Ok(()),
+            false => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(anyhow!(
+                    "{}",
+                    stderr
+                        .lines()
+                        .find(|line| line.contains("Error") || line.contains("error"))
+                        .unwrap_or(stderr.trim())
+                ))
+            }
+        }
+    }
+}
+
+/// The assembly code in compiler `output`, without anything printed before it.
+fn assembly(output: &str) -> Option<String> {
+    if let Some(start) = output.find(START) {
+        return Some(String::from(&output[start..]));
+    }
+
+    let body = output.find(BODY)?;
+    let body = output[..body].rfind('\n').map_or(0, |line| line + 1);
+    Some(complete(&output[body..]))
+}
+
+/// Clear the stack frame at the start of the program body in `code`, and
+/// print `size` bytes of it at the start of the epilogue.
+fn instrument(code: &str, size: i64) -> anyhow::Result<String> {
+    let line = |marker: &str| {
+        let index = code
+            .find(marker)
+            .ok_or_else(|| anyhow!("no `{}` line found", marker))?;
+        Ok::<_, anyhow::Error>(code[..index].rfind('\n').map_or(0, |line| line + 1))
+    };
+    let body = line(BODY)?;
+    let body = code[body..]
+        .find('\n')
+        .map_or(code.len(), |end| body + end + 1);
+    let epilogue = line(EPILOGUE)?;
+
+    if epilogue < body {
+        return Err(anyhow!("`{}` line found before `{}`", EPILOGUE, BODY));
+    }
+
+    Ok(format!(
+        "{}{}{}{}{}",
+        &code[..body],
+        CLEAR,
+        &code[body..epilogue],
+        dump(size),
+        &code[epilogue..]
+    ))
+}
+
+/// Added at the start of the epilogue: print `size` bytes of the stack frame,
+/// keeping `%r9` (the saved `%rbx`) and the stack alignment.
+fn dump(size: i64) -> String {
+    format!(
+        "\tpushq\t%r9
+\tpushq\t%r9
+\tmovq\t%rbp, %rdi
+\tmovq\t${}, %rsi
+\tcall\tcs375_frame
+\tpopq\t%r9
+\tpopq\t%r9
+",
+        size
+    )
+}
+
+/// Size of a stack frame big enough for every offset from `%rbp` that `code`
+/// uses.
+fn frame(code: &str) -> i64 {
+    let deepest = code
+        .match_indices("(%rbp")
+        .filter_map(|(index, _)| {
+            let before = &code[..index];
+            let start = before
+                .rfind(|char: char| !char.is_ascii_digit() && char != '-')
+                .map_or(0, |start| start + 1);
+            before[start..].parse::<i64>().ok()
+        })
+        .min()
+        .unwrap_or(0);
+    ((-deepest).max(16) + 15) / 16 * 16
+}
+
+/// Add the entry code from `asmentry` in `genasm.c` to `code` that starts at
+/// `begin Your code`, as samples do, with a stack frame big enough for every
+/// offset from `%rbp` that `code` uses.
+fn complete(code: &str) -> String {
+    format!(
+        "        .text
+        .globl graph1
+        .type   graph1, @function
+graph1:
+.LFB0:
+\t.cfi_startproc
+\tpushq\t%rbp
+\t.cfi_def_cfa_offset 16
+\tmovq\t%rsp, %rbp
+\t.cfi_offset 6, -16
+\t.cfi_def_cfa_register 6
+        subq\t${}, %rsp
+\tmovq\t%rbx, %r9
+{}",
+        frame(code),
+        code
+    )
+}
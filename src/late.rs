@@ -0,0 +1,268 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::metadata::Metadata;
+use crate::report::Report;
+
+/// A local date and time without a time zone, as stored in ZIP entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Timestamp {
+    /// Seconds since 1970-01-01 00:00:00.
+    fn seconds(&self) -> i64 {
+        // Days from civil, from http://howardhinnant.github.io/date_algorithms.html
+        let (month, day) = (i64::from(self.month), i64::from(self.day));
+        let year = i64::from(self.year) - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * 86_400
+            + i64::from(self.hour) * 3_600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+}
+
+impl From<zip::DateTime> for Timestamp {
+    fn from(time: zip::DateTime) -> Self {
+        Timestamp {
+            year: time.year(),
+            month: time.month(),
+            day: time.day(),
+            hour: time.hour(),
+            minute: time.minute(),
+            second: time.second(),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Parses `YYYY-MM-DD HH:MM[:SS]` (or with a `T` separator). A bare
+/// `YYYY-MM-DD` means the end of that day.
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+    fn from_str(timestamp: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "Invalid timestamp `{}`, expected YYYY-MM-DD HH:MM:SS",
+                timestamp
+            )
+        };
+
+        let (date, time) = timestamp
+            .trim()
+            .split_once([' ', 'T'])
+            .unwrap_or((timestamp.trim(), "23:59:59"));
+
+        let date = date
+            .split('-')
+            .map(str::parse::<u16>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let time = time
+            .split(':')
+            .map(str::parse::<u8>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+
+        match (date.as_slice(), time.as_slice()) {
+            (&[year, month, day], &[hour, minute, ref second @ ..])
+                if (1..=12).contains(&month)
+                    && (1..=31).contains(&day)
+                    && hour < 24
+                    && minute < 60
+                    && second.len() <= 1
+                    && second.iter().all(|second| *second < 60) =>
+            {
+                Ok(Timestamp {
+                    year,
+                    month: month as u8,
+                    day: day as u8,
+                    hour,
+                    minute,
+                    second: second.first().copied().unwrap_or(0),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.to_string()
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = anyhow::Error;
+    fn try_from(timestamp: String) -> Result<Self, Self::Error> {
+        timestamp.parse()
+    }
+}
+
+/// Extra days granted to a single student, written `student=days`.
+#[derive(Clone, Debug)]
+pub struct Extension {
+    pub student: String,
+    pub days: u32,
+}
+
+impl FromStr for Extension {
+    type Err = anyhow::Error;
+    fn from_str(extension: &str) -> Result<Self, Self::Err> {
+        let (student, days) = extension
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid extension `{}`, expected STUDENT=DAYS", extension))?;
+        Ok(Extension {
+            student: String::from(student),
+            days: days
+                .parse()
+                .map_err(|_| anyhow!("Invalid number of days in extension `{}`", extension))?,
+        })
+    }
+}
+
+/// How late submissions are penalized.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Percent of the earned score deducted for each day late.
+    pub percent_per_day: u32,
+
+    /// Timestamps after this count as late, in the same time zone as the
+    /// submissions ZIP. Without a deadline, a `LATE` submission is one day late.
+    pub deadline: Option<Timestamp>,
+
+    /// Days added to the deadline for individual students.
+    pub extensions: BTreeMap<String, u32>,
+}
+
+/// Late penalty applied to a report.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Late {
+    pub days: u32,
+
+    /// Points deducted from the earned score.
+    pub penalty: u32,
+}
+
+impl Policy {
+    /// Days late after any extension, or 0 if on time.
+    pub fn days_late(&self, student: &str, metadata: &Metadata) -> u32 {
+        // Canvas knows about due date overrides that we don't, so its flag
+        // decides whether a submission is late, and the timestamps how late
+        if !metadata.files.iter().any(|file| file.late) {
+            return 0;
+        }
+
+        let latest = metadata.files.iter().filter_map(|file| file.modified).max();
+        let measured = match (self.deadline, latest) {
+            (Some(deadline), Some(latest)) if latest > deadline => {
+                let seconds = latest.seconds() - deadline.seconds();
+                u32::try_from((seconds + 86_399) / 86_400).unwrap_or(u32::MAX)
+            }
+            _ => 0,
+        };
+
+        measured
+            .max(1)
+            .saturating_sub(self.extensions.get(student).copied().unwrap_or(0))
+    }
+
+    /// Record lateness in `report`, and deduct the penalty from its score.
+    /// The penalty is also spread over the tests (largest remainder first),
+    /// so their scores still add up to the total.
+    pub fn apply(&self, report: &mut Report, metadata: &Metadata) {
+        let days = self.days_late(&report.student, metadata);
+        if days == 0 {
+            return;
+        }
+
+        let percent = days.saturating_mul(self.percent_per_day).min(100);
+        let penalty = report.score.earned * percent / 100;
+
+        let mut shares = report
+            .tests
+            .iter()
+            .enumerate()
+            .map(|(index, test)| (test.score.earned * percent, index))
+            .collect::<Vec<_>>();
+        let mut left = penalty - shares.iter().map(|(share, _)| share / 100).sum::<u32>();
+        shares.sort_by_key(|(share, _)| Reverse(share % 100));
+
+        for (share, index) in shares {
+            let extra = u32::from(left > 0 && share % 100 > 0);
+            left -= extra;
+            report.tests[index].score.earned -= share / 100 + extra;
+        }
+
+        report.score.earned -= penalty;
+        report.late = Some(Late { days, penalty });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+    use crate::metadata::Metadata;
+    use crate::metadata::Submitted;
+    use crate::report::Report;
+    use crate::report::Test;
+    use crate::score::Score;
+
+    #[test]
+    fn penalty_spread_over_tests() {
+        let mut report = Report::new("lastfirst");
+        for (name, earned) in [("a", 1), ("b", 1), ("c", 1), ("d", 3)] {
+            let score = Score { earned, total: 3 };
+            report.push(Test::new(String::from(name), score, Vec::new(), None));
+        }
+
+        let metadata = Metadata {
+            files: vec![Submitted {
+                name: String::from("parse.y"),
+                source: String::from("lastfirst_LATE_1_2_parse.y"),
+                late: true,
+                modified: None,
+            }],
+            fallbacks: Vec::new(),
+        };
+        let policy = Policy {
+            percent_per_day: 50,
+            ..Policy::default()
+        };
+        policy.apply(&mut report, &metadata);
+
+        assert_eq!(report.late.unwrap().penalty, 3);
+        assert_eq!(report.score.earned, 3);
+        let earned = report.tests.iter().map(|test| test.score.earned);
+        assert_eq!(earned.sum::<u32>(), report.score.earned);
+    }
+}
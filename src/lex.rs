@@ -1,43 +1,40 @@
-use std::env;
-use std::io::Write as _;
-use std::iter;
 use std::path::Path;
-use std::process::Command;
-use std::process::Stdio;
-use std::str;
-
-use ansi_term::Color;
-use anyhow::anyhow;
-use anyhow::Context as _;
-use difference::Changeset;
+
 use difference::Difference;
 use include_dir::include_dir;
 use include_dir::Dir;
 
+use crate::build::Builder;
+use crate::build::Recipe;
+use crate::report;
+use crate::report::Report;
+use crate::run;
+use crate::run::Exit;
+use crate::run::Options;
+use crate::sandbox::Sandbox;
+use crate::score::Mismatch;
+use crate::score::Rubric;
+
 static TESTS: Dir = include_dir!("$CARGO_MANIFEST_DIR/test_p1");
 
+/// Points possible when every test is worth `rubric`.
+pub(crate) fn total(rubric: Rubric) -> u32 {
+    TESTS.files().count() as u32 * rubric.total()
+}
+
 pub fn grade<P: AsRef<Path>, F>(
     workspace: P,
-    verbose: bool,
-    target: &str,
+    recipes: &[Recipe],
     expecteds: &Dir,
-    mut different: F,
-) -> anyhow::Result<()>
+    rubric: Rubric,
+    options: &Options,
+    mut compare: F,
+) -> anyhow::Result<Report>
 where
-    F: FnMut(
-        &mut iter::Peekable<str::Split<char>>,
-        &mut iter::Peekable<str::Split<char>>,
-    ) -> anyhow::Result<bool>,
+    F: FnMut(&[&str], &[&str]) -> anyhow::Result<Vec<Difference>>,
 {
-    let student = workspace.as_ref().file_name().unwrap();
-
-    println!(
-        "[{}] grading in workspace {}...",
-        student.to_string_lossy(),
-        workspace.as_ref().display()
-    );
-
-    env::set_current_dir(&workspace)?;
+    let mut report = Report::new(workspace.as_ref());
+    let sandbox = Sandbox::new(workspace.as_ref().canonicalize()?, options)?;
 
     let mut tests = TESTS.files().collect::<Vec<_>>();
     let mut expecteds = expecteds.files().collect::<Vec<_>>();
@@ -45,117 +42,402 @@ where
     tests.sort_by_key(|file| file.path().file_name().unwrap());
     expecteds.sort_by_key(|file| file.path().file_name().unwrap());
 
-    match Command::new("make")
-        .arg(target)
-        .spawn()
-        .context("Could not execute `make`")?
-        .wait()
-        .map_err(anyhow::Error::new)
-        .and_then(|status| {
-            if status.success() {
-                Ok(status)
-            } else {
-                Err(anyhow!(status))
-            }
-        })
-        .with_context(|| anyhow!("Could not execute `make {}`", target))
-    {
-        Ok(_) => (),
-        Err(error) => {
-            println!("{}", error);
+    let lexer = match Builder::new(&sandbox)?.build(recipes, &mut report) {
+        Ok(lexer) => lexer,
+        Err(failure) => {
+            let tests = tests.iter().map(|test| {
+                let name = test.path().file_name().unwrap().to_string_lossy();
+                (name.into_owned(), rubric.total())
+            });
+            report.not_built(&failure, tests);
+            return Ok(report);
         }
-    }
-
-    let mut failures = 0;
+    };
 
     for (test, expected) in tests.iter().zip(&expecteds) {
-        let differences = grade_test(target, test, expected, &mut different)
-            .with_context(|| anyhow!("Failed to grade test {}", test.path().display()))?;
         let name = test.path().file_name().unwrap().to_string_lossy();
+        let (differences, exit, stderr) =
+            match grade_test(&sandbox, &lexer.path, test, expected, options, &mut compare) {
+                Ok(result) => result,
+                Err(error) => {
+                    report.push(report::Test::error(
+                        name.into_owned(),
+                        rubric.total(),
+                        &error,
+                    ));
+                    continue;
+                }
+            };
+        let mut result = report::Test::new(
+            name.into_owned(),
+            rubric.score(&mismatches(&differences, exit)),
+            differences,
+            Some(exit),
+        );
 
-        match differences.is_empty() {
-            true if verbose => println!("- [{}]: pass", name),
-            true => (),
-            false => {
-                println!("- [{}]: fail", name);
-                failures += 1;
-            }
+        if result.status == report::Status::Fail {
+            result.source = test.contents_utf8().map(String::from);
         }
 
-        for difference in differences {
-            match difference {
-                difference::Difference::Same(_) => (),
-                difference::Difference::Add(added) => {
-                    print!("{}", Color::Green.paint("+ "));
-                    println!("{}", Color::Green.paint(added));
-                }
-                difference::Difference::Rem(removed) => {
-                    print!("{}", Color::Red.paint("- "));
-                    println!("{}", Color::Red.paint(removed));
-                }
-            }
-        }
+        result.stderr = stderr;
+        report.push(result);
     }
 
-    println!(
-        "{}",
-        Color::Blue.paint(format!(
-            "[{}]: passed {} out of {}",
-            student.to_string_lossy(),
-            tests.len() - failures,
-            tests.len()
-        ))
-    );
+    Ok(report)
+}
+
+/// A wrong line if any line differs, ignoring lines that only matched the
+/// sample as tokens (e.g. a float within tolerance), and a timeout.
+pub(crate) fn mismatches(differences: &[Difference], exit: Exit) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
 
-    Ok(())
+    if differences
+        .iter()
+        .any(|difference| !matches!(difference, Difference::Same(_)))
+    {
+        mismatches.push(Mismatch::Line);
+    }
+
+    if exit.killed() {
+        mismatches.push(Mismatch::Killed);
+    }
+
+    mismatches
 }
 
 fn grade_test<F>(
-    target: &str,
+    sandbox: &Sandbox,
+    lexer: &Path,
     test: &include_dir::File,
     expected: &include_dir::File,
-    mut different: F,
-) -> anyhow::Result<Vec<Difference>>
+    options: &Options,
+    mut compare: F,
+) -> anyhow::Result<(Vec<Difference>, Exit, Option<String>)>
 where
-    F: FnMut(
-        &mut iter::Peekable<str::Split<char>>,
-        &mut iter::Peekable<str::Split<char>>,
-    ) -> anyhow::Result<bool>,
+    F: FnMut(&[&str], &[&str]) -> anyhow::Result<Vec<Difference>>,
 {
-    let mut child = Command::new(format!("./{}", target))
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+    let command = sandbox.command(lexer);
+    let output = run::run(command, test.contents(), options)?;
 
-    child.stdin.as_mut().unwrap().write_all(test.contents())?;
+    // Output is incomplete, so there is no point in comparing it
+    if output.exit.killed() {
+        return Ok((Vec::new(), output.exit, output.stderr()));
+    }
 
     let expected = expected.contents_utf8().unwrap_or_default();
-    let stdout = child.wait_with_output()?.stdout;
-    let actual = String::from_utf8_lossy(&stdout);
+    let actual = String::from_utf8_lossy(&output.stdout);
 
     if actual == expected {
-        return Ok(Vec::new());
+        return Ok((Vec::new(), output.exit, output.stderr()));
+    }
+
+    let expecteds = expected.trim_end_matches('\n').lines().collect::<Vec<_>>();
+    let actuals = actual.trim_end_matches('\n').lines().collect::<Vec<_>>();
+    let mut differences = compare(&expecteds, &actuals)?;
+
+    if mismatches(&differences, output.exit).is_empty() {
+        return Ok((Vec::new(), output.exit, output.stderr()));
+    }
+
+    let source = test.contents_utf8().unwrap_or_default();
+    if source.trim_end().lines().count() > 1 {
+        number(&mut differences, &expecteds, &lexemes(source));
+    }
+
+    Ok((differences, output.exit, output.stderr()))
+}
+
+/// Prefix each difference with the input line of the expected token it
+/// corresponds to. Added lines take the line of the last expected token
+/// before them (or the first after, at the start).
+fn number(differences: &mut [Difference], expecteds: &[&str], lexemes: &[usize]) {
+    // Only `tokentype:` lines consume a lexeme, not e.g. overflow warnings
+    let mut lexeme = 0;
+    let lines = expecteds
+        .iter()
+        .map(|expected| match expected.contains("tokentype:") {
+            true => {
+                lexeme += 1;
+                lexemes.get(lexeme - 1).copied()
+            }
+            false => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut expected = 0;
+    let mut last = None;
+
+    for difference in differences {
+        match difference {
+            Difference::Same(_) => {
+                last = lines[expected].or(last);
+                expected += 1;
+            }
+            Difference::Rem(line) => {
+                if let Some(number) = lines[expected] {
+                    *line = format!("line {}: {}", number, line);
+                    last = Some(number);
+                }
+                expected += 1;
+            }
+            Difference::Add(line) => {
+                let next = || lines[expected..].iter().flatten().next().copied();
+                if let Some(number) = last.or_else(next) {
+                    *line = format!("line {}: {}", number, line);
+                }
+            }
+        }
+    }
+}
+
+/// Line (counting from 1) on which each lexeme of a Pascal program starts.
+/// Only needs to split lexemes the way the scanner does, not classify them.
+fn lexemes(source: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        let start = line;
+        match char {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            char if char.is_whitespace() => continue,
+            '{' => {
+                for char in chars.by_ref() {
+                    match char {
+                        '\n' => line += 1,
+                        '}' => break,
+                        _ => (),
+                    }
+                }
+                continue;
+            }
+            '(' if chars.next_if_eq(&'*').is_some() => {
+                let mut star = false;
+                for char in chars.by_ref() {
+                    match char {
+                        '\n' => line += 1,
+                        ')' if star => break,
+                        _ => (),
+                    }
+                    star = char == '*';
+                }
+                continue;
+            }
+            '\'' => {
+                // A doubled quote stands for one quote inside the string
+                while let Some(char) = chars.next() {
+                    match char {
+                        '\n' => line += 1,
+                        '\'' if chars.next_if_eq(&'\'').is_none() => break,
+                        _ => (),
+                    }
+                }
+            }
+            char if char.is_ascii_alphabetic() => {
+                while chars.next_if(char::is_ascii_alphanumeric).is_some() {}
+            }
+            char if char.is_ascii_digit() => {
+                while chars.next_if(char::is_ascii_digit).is_some() {}
+
+                // Not `..`, as in `1..10`
+                let mut lookahead = chars.clone();
+                if lookahead.next() == Some('.')
+                    && lookahead.next().is_some_and(|char| char.is_ascii_digit())
+                {
+                    chars.next();
+                    while chars.next_if(char::is_ascii_digit).is_some() {}
+                }
+
+                let mut lookahead = chars.clone();
+                if matches!(lookahead.next(), Some('e' | 'E')) {
+                    lookahead.next_if(|char| matches!(char, '+' | '-'));
+                    if lookahead.peek().is_some_and(char::is_ascii_digit) {
+                        chars = lookahead;
+                        while chars.next_if(char::is_ascii_digit).is_some() {}
+                    }
+                }
+            }
+            ':' | '<' | '>' | '.' => {
+                chars.next_if(|next| {
+                    matches!(
+                        (char, next),
+                        (':' | '<' | '>', '=') | ('<', '>') | ('.', '.')
+                    )
+                });
+            }
+            _ => (),
+        }
+        lines.push(start);
+    }
+
+    lines
+}
+
+/// Most cells in the table `align` builds for the lines between a common
+/// prefix and suffix; more are compared line by line instead.
+const CELLS: usize = 1 << 22;
+
+/// Align `expecteds` with `actuals` by their longest common subsequence, where
+/// `equals(i, j)` decides whether `expecteds[i]` matches `actuals[j]`. A single
+/// missing or extra line then shows up as a single difference, rather than
+/// shifting every line after it.
+pub(crate) fn align<F>(expecteds: &[&str], actuals: &[&str], mut equals: F) -> Vec<Difference>
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let (rows, columns) = (expecteds.len(), actuals.len());
+    let prefix = (0..rows.min(columns)).take_while(|&i| equals(i, i)).count();
+    let suffix = (0..rows.min(columns) - prefix)
+        .take_while(|&k| equals(rows - 1 - k, columns - 1 - k))
+        .count();
+
+    let expecteds = &expecteds[prefix..rows - suffix];
+    let middle = &actuals[prefix..columns - suffix];
+    let equals = |i, j| equals(prefix + i, prefix + j);
+
+    let mut differences = same(&actuals[..prefix]);
+    differences.extend(match expecteds.len() * middle.len() <= CELLS {
+        true => subsequence(expecteds, middle, equals),
+        false => pairwise(expecteds, middle, equals),
+    });
+    differences.extend(same(&actuals[columns - suffix..]));
+    differences
+}
+
+fn same(lines: &[&str]) -> Vec<Difference> {
+    lines
+        .iter()
+        .map(|line| Difference::Same(line.to_string()))
+        .collect()
+}
+
+fn subsequence<F>(expecteds: &[&str], actuals: &[&str], mut equals: F) -> Vec<Difference>
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let (rows, columns) = (expecteds.len(), actuals.len());
+    let index = |i: usize, j: usize| i * (columns + 1) + j;
+
+    let mut matches = vec![false; rows * columns];
+    for (i, j) in (0..rows).flat_map(|i| (0..columns).map(move |j| (i, j))) {
+        matches[i * columns + j] = equals(i, j);
+    }
+
+    // Length of the longest common subsequence of `expecteds[i..]` and `actuals[j..]`
+    let mut lengths = vec![0usize; (rows + 1) * (columns + 1)];
+    for i in (0..rows).rev() {
+        for j in (0..columns).rev() {
+            lengths[index(i, j)] = match matches[i * columns + j] {
+                true => lengths[index(i + 1, j + 1)] + 1,
+                false => lengths[index(i + 1, j)].max(lengths[index(i, j + 1)]),
+            };
+        }
     }
 
     let mut differences = Vec::new();
-    let mut expecteds = expected.trim_end_matches('\n').split('\n').peekable();
-    let mut actuals = actual.trim_end_matches('\n').split('\n').peekable();
+    let (mut i, mut j) = (0, 0);
 
-    while let (Some(expected), Some(actual)) = (expecteds.peek().copied(), actuals.peek().copied())
-    {
-        if different(&mut expecteds, &mut actuals)? {
-            differences.append(&mut Changeset::new(expected, actual, "\n").diffs);
+    while i < rows || j < columns {
+        if i < rows && j < columns && matches[i * columns + j] {
+            differences.push(Difference::Same(actuals[j].to_string()));
+            i += 1;
+            j += 1;
+        } else if i < rows && (j == columns || lengths[index(i + 1, j)] >= lengths[index(i, j + 1)])
+        {
+            differences.push(Difference::Rem(expecteds[i].to_string()));
+            i += 1;
+        } else {
+            differences.push(Difference::Add(actuals[j].to_string()));
+            j += 1;
         }
     }
 
-    for expected in expecteds {
-        differences.append(&mut Changeset::new(expected, "", "\n").diffs);
+    differences
+}
+
+/// Compare the `i`th line of `expecteds` with the `i`th of `actuals`, for
+/// output too large to align.
+fn pairwise<F>(expecteds: &[&str], actuals: &[&str], mut equals: F) -> Vec<Difference>
+where
+    F: FnMut(usize, usize) -> bool,
+{
+    let mut differences = Vec::new();
+
+    for i in 0..expecteds.len().max(actuals.len()) {
+        match (expecteds.get(i), actuals.get(i)) {
+            (Some(_), Some(actual)) if equals(i, i) => {
+                differences.push(Difference::Same(actual.to_string()))
+            }
+            (expected, actual) => {
+                differences.extend(expected.map(|line| Difference::Rem(line.to_string())));
+                differences.extend(actual.map(|line| Difference::Add(line.to_string())));
+            }
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use difference::Difference;
+
+    use super::align;
+
+    fn differences(expecteds: &[&str], actuals: &[&str]) -> Vec<Difference> {
+        align(expecteds, actuals, |i, j| expecteds[i] == actuals[j])
     }
 
-    for actual in actuals {
-        differences.append(&mut Changeset::new("", actual, "\n").diffs);
+    #[test]
+    fn missing_line() {
+        let expecteds = ["a", "b", "c", "d"];
+        assert_eq!(
+            differences(&expecteds, &["a", "c", "d"]),
+            [
+                Difference::Same(String::from("a")),
+                Difference::Rem(String::from("b")),
+                Difference::Same(String::from("c")),
+                Difference::Same(String::from("d")),
+            ]
+        );
+        assert_eq!(
+            differences(&expecteds, &["x", "a", "b", "c", "d"]),
+            [
+                Difference::Add(String::from("x")),
+                Difference::Same(String::from("a")),
+                Difference::Same(String::from("b")),
+                Difference::Same(String::from("c")),
+                Difference::Same(String::from("d")),
+            ]
+        );
     }
 
-    Ok(differences)
+    #[test]
+    fn large_output() {
+        // Too many lines to align, e.g. from a lexer stuck in a loop
+        let expecteds = ["a", "b", "c"];
+        let mut actuals = vec!["a", "x", "c"];
+        actuals.extend(iter::repeat_n("y", 2 << 20));
+
+        let differences = differences(&expecteds, &actuals);
+        assert_eq!(differences.len(), 4 + (2 << 20));
+        assert_eq!(
+            differences[..4],
+            [
+                Difference::Same(String::from("a")),
+                Difference::Rem(String::from("b")),
+                Difference::Add(String::from("x")),
+                Difference::Same(String::from("c")),
+            ]
+        );
+        assert!(differences[4..]
+            .iter()
+            .all(|difference| *difference == Difference::Add(String::from("y"))));
+    }
 }
@@ -1,7 +1,20 @@
+mod asm;
+mod build;
 mod lex;
 mod parse;
 
+pub mod archive;
+pub mod canvas;
+pub mod execute;
+pub mod late;
+pub mod metadata;
 pub mod p1;
 pub mod p2;
 pub mod p3;
 pub mod p4;
+pub mod p5;
+pub mod p6;
+pub mod report;
+pub mod run;
+pub mod sandbox;
+pub mod score;
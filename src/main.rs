@@ -1,20 +1,42 @@
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufReader;
-use std::io::BufWriter;
+use std::io::Read as _;
+use std::io::Write as _;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use clap::Parser;
 use zip::read::ZipArchive;
 
+use cs375_autograder::archive;
+use cs375_autograder::canvas::Submission;
+use cs375_autograder::execute::Driver;
+use cs375_autograder::late::Extension;
+use cs375_autograder::late::Policy;
+use cs375_autograder::late::Timestamp;
+use cs375_autograder::metadata;
+use cs375_autograder::metadata::Metadata;
 use cs375_autograder::p1;
+use cs375_autograder::p1::Tolerance;
 use cs375_autograder::p2;
 use cs375_autograder::p3;
 use cs375_autograder::p4;
+use cs375_autograder::p5;
+use cs375_autograder::p6;
+use cs375_autograder::report;
+use cs375_autograder::report::Report;
+use cs375_autograder::run::Options;
+use cs375_autograder::sandbox::Limits;
 
 #[derive(Parser)]
 #[clap(about)]
@@ -28,6 +50,25 @@ enum Command {
         #[clap(long, default_value = "cs375_minimal")]
         skeleton: PathBuf,
 
+        /// Project being prepared, which determines the default protected files.
+        #[clap(short, long)]
+        project: Option<Project>,
+
+        /// Skeleton files that submissions may not replace (overrides the
+        /// project's defaults).
+        #[clap(long, use_delimiter = true)]
+        protect: Option<Vec<String>>,
+
+        /// Earlier project's workspace root or submissions ZIP, to copy
+        /// missing fallback files (e.g. `lexan.l`) from.
+        #[clap(long)]
+        fallback: Option<PathBuf>,
+
+        /// Files to copy from `--fallback` if missing (overrides the
+        /// project's defaults).
+        #[clap(long, use_delimiter = true)]
+        fallback_files: Option<Vec<String>>,
+
         /// Directory to output unzipped student code, with the skeleton code.
         workspace: PathBuf,
     },
@@ -41,6 +82,57 @@ enum Command {
         #[clap(short, long)]
         verbose: bool,
 
+        /// Grade only this unit test number (p6 only).
+        #[clap(short, long)]
+        test: Option<usize>,
+
+        /// How close floating point numbers must be to the sample (p1 only):
+        /// digit, absolute:EPSILON, or relative:EPSILON.
+        #[clap(long, default_value = "digit")]
+        float_tolerance: Tolerance,
+
+        /// Also assemble, link, and run the generated code of each test,
+        /// and compare its output and the variables it leaves with the
+        /// sample's (p6 only, x86-64).
+        #[clap(long)]
+        execute: bool,
+
+        /// C source for `main` and the runtime library to link generated
+        /// code with, such as the course's `driver.c`. The built-in stand-in
+        /// only approximates it (e.g. reals are printed with `%g`).
+        #[clap(long, requires = "execute")]
+        driver: Option<PathBuf>,
+
+        /// Wall-clock timeout (in seconds) for each run of a student binary.
+        #[clap(long, default_value = "5")]
+        timeout: u64,
+
+        /// Output format (one of text, json, csv).
+        #[clap(short, long, default_value = "text")]
+        format: Format,
+
+        /// Number of workspaces to grade concurrently.
+        #[clap(short, long, default_value = "1")]
+        jobs: usize,
+
+        /// Run `make` and student binaries with resource limits, a private
+        /// temp directory, and no write access outside the workspace.
+        #[clap(long)]
+        sandbox: bool,
+
+        /// Percent of the score deducted for each day late.
+        #[clap(long, default_value = "0")]
+        late_penalty: u32,
+
+        /// Submission deadline (YYYY-MM-DD HH:MM:SS), in the time zone of
+        /// the submissions ZIP. Without it, LATE submissions are one day late.
+        #[clap(long)]
+        deadline: Option<Timestamp>,
+
+        /// Extra days for a student, as STUDENT=DAYS.
+        #[clap(long, use_delimiter = true)]
+        extension: Vec<Extension>,
+
         workspaces: Vec<PathBuf>,
     },
 }
@@ -58,6 +150,77 @@ enum Project {
 
     /// Parse (graph1.pas)
     P4,
+
+    /// Parse (pasrec.pas)
+    P5,
+
+    /// Code generation
+    P6,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// Colored, human-readable text
+    Text,
+
+    /// A single JSON document containing every report
+    Json,
+
+    /// One record per student and test
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(anyhow!("Invalid format `{}`", format)),
+        }
+    }
+}
+
+impl Project {
+    /// Skeleton files that fix the output format the rubrics depend on,
+    /// plus each project's driver.
+    fn protected(project: Option<Self>) -> Vec<&'static str> {
+        let drivers: &[&str] = match project {
+            Some(Project::P1) => &["lexandr.c"],
+            Some(Project::P2) => &["lexanl.c"],
+            Some(Project::P6) => &["genasm.c", "genasm.h"],
+            None | Some(Project::P3) | Some(Project::P4) | Some(Project::P5) => &[],
+        };
+
+        ["makefile", "printtoken.c", "pprint.c", "pprint.h"]
+            .into_iter()
+            .chain(drivers.iter().copied())
+            .collect()
+    }
+
+    /// Points possible in this project, reported for workspaces that
+    /// couldn't be graded at all.
+    fn total(self) -> u32 {
+        match self {
+            Project::P1 => p1::total(),
+            Project::P2 => p2::total(),
+            Project::P3 => p3::total(),
+            Project::P4 => p4::total(),
+            Project::P5 => p5::total(),
+            Project::P6 => p6::total(),
+        }
+    }
+
+    /// Files that may be reused from an earlier project (see `rubrics/p3.md`
+    /// and `rubrics/p6.md`).
+    fn fallbacks(project: Option<Self>) -> &'static [&'static str] {
+        match project {
+            Some(Project::P3) | Some(Project::P4) | Some(Project::P5) => &["lexan.l", "lexanc.c"],
+            Some(Project::P6) => &["lexan.l", "lexanc.c", "parse.y"],
+            None | Some(Project::P1) | Some(Project::P2) => &[],
+        }
+    }
 }
 
 impl FromStr for Project {
@@ -68,6 +231,8 @@ impl FromStr for Project {
             "2" | "p2" | "P2" => Ok(Project::P2),
             "3" | "p3" | "P3" => Ok(Project::P3),
             "4" | "p4" | "P4" => Ok(Project::P4),
+            "5" | "p5" | "P5" => Ok(Project::P5),
+            "6" | "p6" | "P6" => Ok(Project::P6),
             _ => Err(anyhow!("Invalid project `{}`", project)),
         }
     }
@@ -80,39 +245,37 @@ fn main() -> anyhow::Result<()> {
         Command::Prepare {
             submissions,
             skeleton,
+            project,
+            protect,
+            fallback,
+            fallback_files,
             workspace,
         } => {
             fs::create_dir_all(&workspace)?;
 
-            let mut workspace = workspace.canonicalize()?;
-            let mut archives = Vec::new();
-            let mut students = BTreeMap::default();
-
-            for (index, archive) in submissions.iter().enumerate() {
-                let archive = File::open(&archive)
-                    .map(BufReader::new)
-                    .map(ZipArchive::new)??;
-
-                archive
-                    .file_names()
-                    .filter_map(|path| {
-                        let (student, _) = path.split_once('_')?;
-                        let (name, extension) = path.rsplit_once('.')?;
-                        let (_, name) = name
-                            .trim_end_matches(|char| matches!(char, '-' | '0'..='9'))
-                            .rsplit_once('_')?;
-
-                        Some((student, path, format!("{}.{}", name, extension)))
-                    })
-                    .for_each(|(student, path, name)| {
-                        students
-                            .entry(String::from(student))
-                            .or_insert_with(BTreeMap::new)
-                            .insert(name, (index, String::from(path)));
-                    });
+            let protected = protect.unwrap_or_else(|| {
+                Project::protected(project)
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            });
 
-                archives.push(archive);
-            }
+            let fallbacks = fallback_files.unwrap_or_else(|| {
+                Project::fallbacks(project)
+                    .iter()
+                    .copied()
+                    .map(String::from)
+                    .collect()
+            });
+
+            let mut fallback = match fallback {
+                None => None,
+                Some(path) if path.is_dir() => Some(Previous::Workspace(path)),
+                Some(path) => Some(Previous::Submissions(Submissions::open(vec![path])?)),
+            };
+
+            let mut workspace = workspace.canonicalize()?;
+            let mut submissions = Submissions::open(submissions)?;
 
             let skeletons = Path::new(&skeleton)
                 .canonicalize()?
@@ -121,35 +284,111 @@ fn main() -> anyhow::Result<()> {
                 .map(|entry| entry.path())
                 .collect::<Vec<_>>();
 
-            for (student, paths) in &students {
+            let students = submissions.students.keys().cloned().collect::<Vec<_>>();
+
+            for student in &students {
                 workspace.push(student);
                 fs::create_dir(&workspace)?;
 
                 for skeleton in &skeletons {
                     workspace.push(skeleton.file_name().unwrap());
-                    fs::copy(&skeleton, &workspace)?;
+                    fs::copy(skeleton, &workspace)?;
                     workspace.pop();
                 }
 
-                for (name, (index, path)) in paths {
-                    workspace.push(name);
+                // Loose files take precedence over files in archives
+                let mut copied = BTreeMap::new();
+                let mut metadata = Metadata::default();
+
+                for upload in submissions.files(student)? {
+                    let Upload {
+                        name,
+                        source,
+                        late,
+                        modified,
+                        contents,
+                    } = upload;
+
+                    metadata.files.push(metadata::Submitted {
+                        name: name.clone(),
+                        source: source.clone(),
+                        late,
+                        modified,
+                    });
+
+                    if protected.contains(&name) {
+                        eprintln!(
+                            "[{}]: skipping protected file {}, keeping skeleton {}",
+                            student, source, name
+                        );
+                        continue;
+                    }
 
+                    if let Some(existing) = copied.get(&name) {
+                        eprintln!(
+                            "[{}]: conflict: skipping {}, already copied {} from {}",
+                            student, source, name, existing
+                        );
+                        continue;
+                    }
+
+                    workspace.push(&name);
                     eprintln!(
-                        "[{}]: copying submission {}/{} to {}",
+                        "[{}]: copying submission {} to {}",
                         student,
-                        submissions[*index].display(),
-                        path,
+                        source,
                         workspace.display()
                     );
+                    fs::write(&workspace, contents)?;
+                    workspace.pop();
+
+                    copied.insert(name, source);
+                }
+
+                for name in &fallbacks {
+                    if copied.contains_key(name) {
+                        continue;
+                    }
 
-                    std::io::copy(
-                        &mut archives[*index].by_name(path)?,
-                        &mut fs::File::create(&workspace).map(BufWriter::new)?,
-                    )?;
+                    let found = match &mut fallback {
+                        None => None,
+                        Some(Previous::Workspace(root)) => {
+                            let path = root.join(student).join(name);
+                            match path.is_file() {
+                                true => Some((path.display().to_string(), fs::read(&path)?)),
+                                false => None,
+                            }
+                        }
+                        Some(Previous::Submissions(previous)) => previous
+                            .files(student)?
+                            .into_iter()
+                            .find(|upload| upload.name == *name)
+                            .map(|upload| (upload.source, upload.contents)),
+                    };
 
+                    let (source, contents) = match found {
+                        Some(found) => found,
+                        None => continue,
+                    };
+
+                    workspace.push(name);
+                    eprintln!(
+                        "[{}]: {} not submitted, copying fallback {} to {}",
+                        student,
+                        name,
+                        source,
+                        workspace.display()
+                    );
+                    fs::write(&workspace, contents)?;
                     workspace.pop();
+
+                    metadata.fallbacks.push(metadata::Fallback {
+                        name: name.clone(),
+                        source,
+                    });
                 }
 
+                metadata.save(&workspace)?;
                 workspace.pop();
             }
         }
@@ -158,23 +397,256 @@ fn main() -> anyhow::Result<()> {
             workspaces,
             project,
             verbose,
+            test,
+            float_tolerance,
+            execute,
+            driver,
+            timeout,
+            format,
+            jobs,
+            sandbox,
+            late_penalty,
+            deadline,
+            extension,
         } => {
-            for workspace in workspaces {
-                match match project {
-                    Project::P1 => p1::grade(&workspace, verbose),
-                    Project::P2 => p2::grade(&workspace, verbose),
-                    Project::P3 => p3::grade(&workspace, verbose),
-                    Project::P4 => p4::grade(&workspace, verbose),
-                } {
-                    Ok(()) => (),
-                    Err(error) => {
-                        eprintln!("Error grading workspace: {}", workspace.display());
-                        eprintln!("{:?}", error);
-                    }
+            let driver = match (execute, driver) {
+                (false, _) => None,
+                (true, None) => Some(Driver::builtin()),
+                (true, Some(path)) => Some(Driver::load(&path)?),
+            };
+            let options = Options {
+                timeout: Duration::from_secs(timeout),
+                sandbox: sandbox.then(Limits::default),
+            };
+            let policy = Policy {
+                percent_per_day: late_penalty,
+                deadline,
+                extensions: extension
+                    .into_iter()
+                    .map(|extension| (extension.student, extension.days))
+                    .collect(),
+            };
+
+            let queue = Mutex::new(workspaces.iter().enumerate());
+            let reports = Mutex::new(Vec::new());
+
+            thread::scope(|scope| {
+                for _ in 0..jobs.max(1) {
+                    scope.spawn(|| loop {
+                        let (index, workspace) = match queue.lock().unwrap().next() {
+                            Some(next) => next,
+                            None => break,
+                        };
+
+                        let report = grade(
+                            project,
+                            workspace,
+                            test,
+                            float_tolerance,
+                            driver.as_ref(),
+                            &options,
+                            &policy,
+                        );
+
+                        // Render the whole report before printing, so reports
+                        // from concurrent workers don't interleave
+                        if format == Format::Text {
+                            let mut buffer = Vec::new();
+                            report
+                                .write(&mut buffer, verbose)
+                                .and_then(|()| io::stdout().lock().write_all(&buffer))
+                                .expect("[INTERNAL ERROR]: failed to write report");
+                        }
+
+                        reports.lock().unwrap().push((index, report));
+                    });
                 }
+            });
+
+            let mut reports = reports.into_inner().unwrap();
+            reports.sort_by_key(|(index, _)| *index);
+            let reports = reports
+                .into_iter()
+                .map(|(_, report)| report)
+                .collect::<Vec<_>>();
+
+            match format {
+                Format::Text => (),
+                Format::Json => report::write_json(io::stdout().lock(), &reports)?,
+                Format::Csv => report::write_csv(io::stdout().lock(), &reports)?,
             }
         }
     }
 
     Ok(())
 }
+
+/// Latest version of each file submitted by each student, keyed by student
+/// and then by canonical file name.
+type Students = BTreeMap<String, BTreeMap<String, (Submission, usize, String)>>;
+
+/// Canvas submission downloads.
+struct Submissions {
+    paths: Vec<PathBuf>,
+    archives: Vec<ZipArchive<BufReader<File>>>,
+    students: Students,
+}
+
+/// A file from a student's submission.
+struct Upload {
+    name: String,
+
+    /// Path within the submissions ZIP (and any archive nested inside it).
+    source: String,
+
+    late: bool,
+    modified: Option<Timestamp>,
+    contents: Vec<u8>,
+}
+
+/// Where to find files missing from the current project's submissions.
+enum Previous {
+    /// Root directory of an earlier project's workspaces.
+    Workspace(PathBuf),
+
+    Submissions(Submissions),
+}
+
+impl Submissions {
+    fn open(paths: Vec<PathBuf>) -> anyhow::Result<Self> {
+        let mut archives = Vec::new();
+        let mut students = Students::default();
+
+        for (index, archive) in paths.iter().enumerate() {
+            let archive = File::open(archive)
+                .map(BufReader::new)
+                .map(ZipArchive::new)??;
+
+            for path in archive.file_names() {
+                let submission = match Submission::parse(path) {
+                    Some(submission) => submission,
+                    None => {
+                        eprintln!(
+                            "Skipping {}/{}: not a Canvas submission",
+                            paths[index].display(),
+                            path
+                        );
+                        continue;
+                    }
+                };
+
+                // Keep only the latest version of each file
+                match students
+                    .entry(submission.student.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(submission.name.clone())
+                {
+                    Entry::Vacant(entry) => {
+                        entry.insert((submission, index, String::from(path)));
+                    }
+                    Entry::Occupied(mut entry) => {
+                        if submission.cmp_version(&entry.get().0) == Ordering::Greater {
+                            entry.insert((submission, index, String::from(path)));
+                        }
+                    }
+                }
+            }
+
+            archives.push(archive);
+        }
+
+        Ok(Submissions {
+            paths,
+            archives,
+            students,
+        })
+    }
+
+    /// Every file `student` submitted. Loose files come first, followed by
+    /// the contents of any archives.
+    fn files(&mut self, student: &str) -> anyhow::Result<Vec<Upload>> {
+        let mut files = Vec::new();
+        let mut nested = Vec::new();
+
+        for (name, (submission, index, path)) in self.students.get(student).into_iter().flatten() {
+            let source = format!("{}/{}", self.paths[*index].display(), path);
+
+            let mut file = self.archives[*index].by_name(path)?;
+            let modified = Timestamp::from(file.last_modified());
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+
+            match archive::extract(name, &contents) {
+                Ok(Some(entries)) => {
+                    nested.extend(entries.into_iter().map(|entry| Upload {
+                        name: entry.name,
+                        source: format!("{}/{}", source, entry.path),
+                        late: submission.late,
+                        modified: Some(modified),
+                        contents: entry.contents,
+                    }));
+                    continue;
+                }
+                Ok(None) => (),
+                Err(error) => eprintln!(
+                    "[{}]: could not extract {}, copying it as is: {:#}",
+                    student, source, error
+                ),
+            }
+
+            files.push(Upload {
+                name: name.clone(),
+                source,
+                late: submission.late,
+                modified: Some(modified),
+                contents,
+            });
+        }
+
+        files.append(&mut nested);
+        Ok(files)
+    }
+}
+
+fn grade(
+    project: Project,
+    workspace: &Path,
+    test: Option<usize>,
+    tolerance: Tolerance,
+    driver: Option<&Driver>,
+    options: &Options,
+    policy: &Policy,
+) -> Report {
+    // Before any student code runs, in case it finds a way to the metadata
+    let metadata = Metadata::load(workspace);
+
+    let report = match project {
+        Project::P1 => p1::grade(workspace, tolerance, options),
+        Project::P2 => p2::grade(workspace, options),
+        Project::P3 => p3::grade(workspace, options),
+        Project::P4 => p4::grade(workspace, options),
+        Project::P5 => p5::grade(workspace, options),
+        Project::P6 => p6::grade(workspace, test, driver, options),
+    };
+
+    let mut report = report.unwrap_or_else(|error| {
+        let mut report = Report::new(workspace);
+        report.score.total = project.total();
+        report.error = Some(format!(
+            "Error grading workspace: {}\n{:?}",
+            workspace.display(),
+            error
+        ));
+        report
+    });
+
+    match metadata {
+        Ok(metadata) => {
+            policy.apply(&mut report, &metadata);
+            report.fallbacks = metadata.fallbacks;
+        }
+        Err(error) => eprintln!("[{}]: {:#}", report.student, error),
+    }
+
+    report
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::late::Timestamp;
+
+/// Name of the directory, next to the workspaces, that `prepare` records
+/// metadata in. It's kept out of the workspaces themselves, where student
+/// code runs and could rewrite it, e.g. to clear its own late flag.
+pub const DIRECTORY: &str = ".cs375-autograder";
+
+/// Information about a workspace that isn't recoverable from its files.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Every file in the student's submission, including any that were skipped.
+    #[serde(default)]
+    pub files: Vec<Submitted>,
+
+    /// Files missing from the submission that were copied from an earlier project.
+    #[serde(default)]
+    pub fallbacks: Vec<Fallback>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Submitted {
+    pub name: String,
+    pub source: String,
+
+    /// Canvas marked the submission `LATE`.
+    pub late: bool,
+
+    /// Timestamp of the entry in the submissions ZIP.
+    pub modified: Option<Timestamp>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fallback {
+    pub name: String,
+
+    /// Earlier workspace or submissions archive the file was copied from.
+    pub source: String,
+}
+
+impl Metadata {
+    /// Read the metadata for `workspace`, or the default if it was prepared by hand.
+    pub fn load(workspace: &Path) -> anyhow::Result<Self> {
+        let path = path(workspace)?;
+        match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| anyhow!("Could not parse {}", path.display())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Metadata::default()),
+            Err(error) => Err(error).with_context(|| anyhow!("Could not read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, workspace: &Path) -> anyhow::Result<()> {
+        let path = path(workspace)?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .with_context(|| anyhow!("Could not create {}", directory.display()))?;
+        }
+        fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| anyhow!("Could not write {}", path.display()))
+    }
+}
+
+/// `workspaces/.cs375-autograder/student.json` for `workspaces/student`.
+fn path(workspace: &Path) -> anyhow::Result<PathBuf> {
+    let workspace = workspace
+        .canonicalize()
+        .with_context(|| anyhow!("Could not find workspace {}", workspace.display()))?;
+
+    match (workspace.parent(), workspace.file_name()) {
+        (Some(root), Some(student)) => Ok(root
+            .join(DIRECTORY)
+            .join(format!("{}.json", student.to_string_lossy()))),
+        _ => Err(anyhow!(
+            "Workspace {} has no parent directory",
+            workspace.display()
+        )),
+    }
+}
@@ -1,62 +1,311 @@
-use std::iter;
-use std::mem;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::Path;
-use std::str;
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 use anyhow::anyhow;
 use anyhow::Context as _;
+use difference::Difference;
 use include_dir::include_dir;
 use include_dir::Dir;
 
+use crate::build::Recipe;
 use crate::lex;
+use crate::report::Report;
+use crate::run::Options;
+use crate::score::Rubric;
 
 static EXPECTEDS: Dir = include_dir!("$CARGO_MANIFEST_DIR/sample_p1");
 
-pub fn grade<P: AsRef<Path>>(workspace: P, verbose: bool) -> anyhow::Result<()> {
-    let mut overflow = None;
-
-    let different = move |expecteds: &mut iter::Peekable<str::Split<char>>,
-                          actuals: &mut iter::Peekable<str::Split<char>>| {
-        let expected = expecteds
-            .next()
-            .expect("[INTERNAL ERROR]: caller guarantees non-None");
-
-        let actual = actuals
-            .next()
-            .expect("[INTERNAL ERROR]: caller guarantees non-None");
-
-        let expected_token = parse(expected).with_context(|| {
-            anyhow!(
-                "[INTERNAL ERROR]: failed to parse expected token: {}",
-                expected
-            )
-        })?;
-
-        let different = match (expected_token, parse(actual)) {
-            (Token::Overflow(expected), Some(Token::Overflow(actual))) => {
-                if expected == actual {
-                    overflow = Some(expected);
-                    false
-                } else {
-                    true
+/// One point for each unit test in `test_p1`, from `rubrics/p1.md`.
+const RUBRIC: Rubric = Rubric::all(1);
+
+const RECIPES: &[Recipe] = &[Recipe {
+    target: "lexanc",
+    binary: "lexanc",
+    requires: &["lexanc.c"],
+    patches: &[],
+}];
+
+pub fn total() -> u32 {
+    lex::total(RUBRIC)
+}
+
+pub fn grade<P: AsRef<Path>>(
+    workspace: P,
+    tolerance: Tolerance,
+    options: &Options,
+) -> anyhow::Result<Report> {
+    lex::grade(
+        workspace,
+        RECIPES,
+        &EXPECTEDS,
+        RUBRIC,
+        options,
+        |expecteds: &[&str], actuals: &[&str]| compare(expecteds, actuals, tolerance),
+    )
+}
+
+/// How close a floating point number must be to the sample.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Tolerance {
+    /// Within this absolute difference.
+    Absolute(f64),
+
+    /// Within this fraction of the larger magnitude.
+    Relative(f64),
+
+    /// Within one unit of the last digit printed in the sample, per
+    /// `rubrics/p1.md`.
+    #[default]
+    LastDigit,
+}
+
+impl FromStr for Tolerance {
+    type Err = anyhow::Error;
+    fn from_str(tolerance: &str) -> Result<Self, Self::Err> {
+        let epsilon = |epsilon: &str| {
+            epsilon
+                .parse::<f64>()
+                .map_err(|_| anyhow!("Invalid tolerance `{}`", tolerance))
+        };
+
+        match tolerance.split_once(':') {
+            None if tolerance == "digit" => Ok(Tolerance::LastDigit),
+            Some(("absolute", value)) => epsilon(value).map(Tolerance::Absolute),
+            Some(("relative", value)) => epsilon(value).map(Tolerance::Relative),
+            _ => Err(anyhow!(
+                "Invalid tolerance `{}`, expected digit, absolute:EPSILON, or relative:EPSILON",
+                tolerance
+            )),
+        }
+    }
+}
+
+impl Tolerance {
+    /// Why `actual` isn't close enough to `expected`, if it isn't.
+    fn reject(&self, expected: &Float, actual: &Float) -> Option<String> {
+        let (left, right) = (expected.value(), actual.value());
+        let difference = (left - right).abs();
+
+        match self {
+            Tolerance::Absolute(epsilon) if difference > *epsilon => Some(format!(
+                "off by {:.3e}, more than the absolute tolerance {:e}",
+                difference, epsilon
+            )),
+            Tolerance::Relative(epsilon) if difference > epsilon * left.abs().max(right.abs()) => {
+                Some(format!(
+                    "off by {:.3e} relative, more than the relative tolerance {:e}",
+                    difference / left.abs().max(right.abs()),
+                    epsilon
+                ))
+            }
+            Tolerance::Absolute(_) | Tolerance::Relative(_) => None,
+            Tolerance::LastDigit => {
+                // Compare exactly, in units of the finer of the two last digits
+                let exponent = expected.exponent.min(actual.exponent);
+                let scale = |float: &Float| {
+                    10i128
+                        .checked_pow((float.exponent - exponent) as u32)
+                        .and_then(|scale| scale.checked_mul(i128::from(float.digits)))
+                };
+                let unit = Float {
+                    digits: 1,
+                    ..*expected
+                };
+
+                match (scale(expected), scale(actual), scale(&unit)) {
+                    (Some(left), Some(right), Some(unit)) if (left - right).abs() <= unit => None,
+                    (Some(left), Some(right), Some(unit)) => Some(format!(
+                        "off by {} in the last printed digit, more than \u{b1}1",
+                        (left - right).abs() as f64 / unit as f64
+                    )),
+                    _ => Some(String::from("orders of magnitude apart")),
                 }
             }
-            (Token::Overflow(_), _) => {
-                expecteds.next();
-                true
+        }
+    }
+}
+
+/// Align tokens rather than lines, so a missing or extra token doesn't
+/// cause every following token to mismatch.
+fn compare(
+    expecteds: &[&str],
+    actuals: &[&str],
+    tolerance: Tolerance,
+) -> anyhow::Result<Vec<Difference>> {
+    let expected_tokens = expecteds
+        .iter()
+        .map(|expected| {
+            parse(expected).map(Some).with_context(|| {
+                anyhow!(
+                    "[INTERNAL ERROR]: failed to parse expected token: {}",
+                    expected
+                )
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let actual_tokens = actuals
+        .iter()
+        .map(|actual| parse(actual))
+        .collect::<Vec<_>>();
+
+    let mut differences = lex::align(expecteds, actuals, |i, j| {
+        match (&expected_tokens[i], &actual_tokens[j]) {
+            (Some(expected), Some(actual)) => {
+                // Out of range numbers are only compared by type, if both
+                // sides reported the same overflow
+                let overflow = match (
+                    Token::overflow(&expected_tokens, i),
+                    Token::overflow(&actual_tokens, j),
+                ) {
+                    (Some(expected), Some(actual)) if expected == actual => Some(expected),
+                    _ => None,
+                };
+                expected.equals(actual, overflow, tolerance)
             }
-            (_, Some(Token::Overflow(_))) => {
-                actuals.next();
-                true
+            (_, None) | (None, _) => false,
+        }
+    });
+
+    // Describe each difference by token, assuming that removed and added
+    // lines in the same block correspond in order
+    let mut start = 0;
+    while start < differences.len() {
+        let removed = differences[start..]
+            .iter()
+            .take_while(|difference| matches!(difference, Difference::Rem(_)))
+            .count();
+        let added = differences[start + removed..]
+            .iter()
+            .take_while(|difference| matches!(difference, Difference::Add(_)))
+            .count();
+
+        for offset in 0..removed.max(added) {
+            let expected = (offset < removed).then_some(start + offset);
+            let actual = (offset < added).then_some(start + removed + offset);
+            let line = |index: Option<usize>| match &differences[index?] {
+                Difference::Rem(line) | Difference::Add(line) => Some(line.as_str()),
+                Difference::Same(_) => None,
+            };
+            let token = |index| line(index).and_then(parse);
+            let unknown = || line(actual).and_then(unknown);
+
+            let note = match (token(expected), token(actual), actual) {
+                (Some(expected), Some(actual), _) => {
+                    let reason = match (&expected, &actual) {
+                        (
+                            Token::Number(Number::Float(left)),
+                            Token::Number(Number::Float(right)),
+                        ) => tolerance.reject(left, right),
+                        _ => None,
+                    };
+
+                    match reason {
+                        Some(reason) => {
+                            format!("expected {}, got {}: {}", expected, actual, reason)
+                        }
+                        None => format!("expected {}, got {}", expected, actual),
+                    }
+                }
+                (Some(expected), None, Some(_)) => format!(
+                    "expected {}, got {}",
+                    expected,
+                    unknown().unwrap_or_else(|| String::from("an unrecognized line"))
+                ),
+                (Some(expected), None, None) => format!("missing {}", expected),
+                (None, Some(actual), _) => format!("unexpected {}", actual),
+                (None, None, _) => match unknown() {
+                    Some(unknown) => unknown,
+                    None => continue,
+                },
+            };
+
+            match &mut differences[actual.or(expected).unwrap()] {
+                Difference::Rem(line) | Difference::Add(line) => {
+                    line.push_str(&format!(" ({})", note))
+                }
+                Difference::Same(_) => unreachable!(),
             }
-            (expected, Some(actual)) => !expected.equals(&actual, mem::take(&mut overflow)),
-            (_, None) => true,
+        }
+
+        start += (removed + added).max(1);
+    }
+
+    Ok(differences)
+}
+
+static TOKEN_H: &str = include_str!("../cs375_minimal/token.h");
+
+/// Names of operators, delimiters, and reserved words by number, from the
+/// Bison token definitions in `token.h`.
+struct Names {
+    operators: BTreeMap<u16, String>,
+    delimiters: BTreeMap<u16, String>,
+    reserved: BTreeMap<u16, String>,
+}
+
+impl Names {
+    fn get() -> &'static Names {
+        static NAMES: OnceLock<Names> = OnceLock::new();
+        NAMES.get_or_init(|| Names::parse(TOKEN_H))
+    }
+
+    fn parse(header: &str) -> Self {
+        let defines = header
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.strip_prefix("#define")?.split_whitespace();
+                let name = words.next()?;
+                let value = words.next()?.parse::<u16>().ok()?;
+                let comment = line
+                    .split_once("/*")
+                    .and_then(|(_, comment)| comment.split_once("*/"))
+                    .map(|(comment, _)| comment.trim());
+                Some((name, value, comment))
+            })
+            .collect::<Vec<_>>();
+
+        // Each group starts at its first token, e.g. `PLUS` for operators
+        let first = |name: &str| {
+            defines
+                .iter()
+                .find(|(define, _, _)| *define == name)
+                .map(|(_, value, _)| *value)
+                .unwrap_or_else(|| panic!("[INTERNAL ERROR]: {} missing from token.h", name))
         };
+        let (plus, comma, array) = (first("PLUS"), first("COMMA"), first("ARRAY"));
 
-        Ok(different)
-    };
+        let mut names = Names {
+            operators: BTreeMap::new(),
+            delimiters: BTreeMap::new(),
+            reserved: BTreeMap::new(),
+        };
 
-    lex::grade(workspace, verbose, "lexanc", &EXPECTEDS, different)
+        for (name, value, comment) in defines {
+            if value >= array {
+                // Names that would collide with C keywords are doubled,
+                // with the actual word in a comment (`BEGINBEGIN /* begin */`)
+                let word = match comment {
+                    Some(word) if word.chars().all(|char| char.is_ascii_lowercase()) => {
+                        String::from(word)
+                    }
+                    _ => name.to_ascii_lowercase(),
+                };
+                names.reserved.insert(value - array + 1, word);
+            } else if value >= comma {
+                names
+                    .delimiters
+                    .insert(value - comma + 1, String::from(name));
+            } else if value >= plus {
+                names.operators.insert(value - plus + 1, String::from(name));
+            }
+        }
+
+        names
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -69,61 +318,205 @@ enum Token {
     Identifier(String),
     String(String),
     Number(Number),
+    Uninitialized(Sentinel),
 }
 
 impl Token {
-    fn equals(&self, other: &Token, overflow: Option<Overflow>) -> bool {
+    /// The overflow reported just before `tokens[index]`, if any.
+    fn overflow(tokens: &[Option<Token>], index: usize) -> Option<Overflow> {
+        match tokens.get(index.checked_sub(1)?)? {
+            Some(Token::Overflow(overflow)) => Some(*overflow),
+            _ => None,
+        }
+    }
+
+    fn equals(&self, other: &Token, overflow: Option<Overflow>, tolerance: Tolerance) -> bool {
         match (self, other) {
-            (Token::Number(left), Token::Number(right)) => left.equals(right, overflow),
+            (Token::Number(left), Token::Number(right)) => left.equals(right, overflow, tolerance),
             _ => self == other,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = |names: &BTreeMap<u16, String>, code: &u16| {
+            names
+                .get(code)
+                .map(String::as_str)
+                .unwrap_or("unknown")
+                .to_string()
+        };
+
+        match self {
+            Token::Start => write!(fmt, "start of scanner test"),
+            Token::Overflow(Overflow::Integer) => write!(fmt, "integer out of range"),
+            Token::Overflow(Overflow::Float) => write!(fmt, "real out of range"),
+            Token::Operator(code) => {
+                write!(
+                    fmt,
+                    "operator `{}` ({})",
+                    name(&Names::get().operators, code),
+                    code
+                )
+            }
+            Token::Delimiter(code) => {
+                write!(
+                    fmt,
+                    "delimiter `{}` ({})",
+                    name(&Names::get().delimiters, code),
+                    code
+                )
+            }
+            Token::Reserved(code) => {
+                write!(
+                    fmt,
+                    "reserved `{}` ({})",
+                    name(&Names::get().reserved, code),
+                    code
+                )
+            }
+            Token::Identifier(value) => write!(
+                fmt,
+                "identifier `{}`",
+                value.trim_start().trim_start_matches("value:").trim()
+            ),
+            Token::String(value) => write!(
+                fmt,
+                "string `{}`",
+                value.trim_start().trim_start_matches("value:").trim()
+            ),
+            Token::Number(Number::Integer(value)) => write!(fmt, "integer {}", value),
+            Token::Number(Number::Float(value)) => write!(fmt, "real {}", value),
+            Token::Uninitialized(sentinel) => {
+                write!(fmt, "uninitialized field in token: {}", sentinel)
+            }
+        }
+    }
+}
+
+/// A field still holding the value that `talloc` in `printtoken.c` fills
+/// new tokens with, so the scanner never assigned it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Sentinel {
+    TokenType,
+    DataType,
+    Which,
+    Integer,
+    String,
+    Unterminated,
+}
+
+/// `talloc` sets `tokentype` and `basicdt` to 9999, and every byte of the
+/// value union to `#`, which reads as this integer.
+const SENTINEL_TYPE: &str = "9999";
+const SENTINEL_INTEGER: &str = "589505315";
+
+impl fmt::Display for Sentinel {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sentinel::TokenType => {
+                write!(
+                    fmt,
+                    "`tokentype` is still 9999, is `tok->tokentype` assigned?"
+                )
+            }
+            Sentinel::DataType => write!(
+                fmt,
+                "`basicdt` is still 9999, is `tok->basicdt` set to INTEGER or REAL?"
+            ),
+            Sentinel::Which => write!(
+                fmt,
+                "`whichval` is still {}, is `tok->whichval` assigned?",
+                SENTINEL_INTEGER
+            ),
+            Sentinel::Integer => write!(
+                fmt,
+                "`intval` is still {}, is `tok->intval` assigned?",
+                SENTINEL_INTEGER
+            ),
+            Sentinel::String => write!(
+                fmt,
+                "`stringval` is still all `#`, is the text copied into `tok->stringval`?"
+            ),
+            Sentinel::Unterminated => write!(
+                fmt,
+                "`stringval` runs into `#` padding, is it terminated with '\\0'?"
+            ),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum Overflow {
     Float,
     Integer,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Number {
-    Float { mantissa: f32, exponent: i32 },
+    Float(Float),
     Integer(i32),
 }
 
 impl Number {
-    fn equals(&self, other: &Number, overflow: Option<Overflow>) -> bool {
+    fn equals(&self, other: &Number, overflow: Option<Overflow>, tolerance: Tolerance) -> bool {
         match (self, other, overflow) {
-            (Number::Float { .. }, Number::Float { .. }, Some(Overflow::Float)) => true,
+            (Number::Float(_), Number::Float(_), Some(Overflow::Float)) => true,
             (Number::Integer(_), Number::Integer(_), Some(Overflow::Integer)) => true,
+            (Number::Float(left), Number::Float(right), _) => {
+                tolerance.reject(left, right).is_none()
+            }
             _ => self == other,
         }
     }
 }
 
-impl PartialEq for Number {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (
-                Self::Float {
-                    mantissa: left_mantissa,
-                    exponent: left_exponent,
-                },
-                Self::Float {
-                    mantissa: right_mantissa,
-                    exponent: right_exponent,
-                },
-            ) => {
-                left_exponent == right_exponent
-                    && (left_mantissa - right_mantissa).abs() <= 0.000_001
-            }
-            (Self::Integer(left), Self::Integer(right)) => left == right,
-            (_, _) => false,
+/// A floating point number exactly as printed: `digits` * 10^`exponent`.
+/// Normalized so that e.g. `9.999999e+05` and `99.99999e+04` are equal.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Float {
+    digits: i64,
+    exponent: i32,
+}
+
+impl Float {
+    fn value(&self) -> f64 {
+        self.digits as f64 * 10f64.powi(self.exponent)
+    }
+}
+
+/// Scientific notation with every parsed digit, e.g. `3.141593e0`.
+impl fmt::Display for Float {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.digits < 0 { "-" } else { "" };
+        let digits = self.digits.unsigned_abs().to_string();
+        let (first, rest) = digits.split_at(1);
+        let exponent = self.exponent + rest.len() as i32;
+        match rest {
+            "" => write!(fmt, "{}{}e{}", sign, first, exponent),
+            _ => write!(fmt, "{}{}.{}e{}", sign, first, rest, exponent),
         }
     }
 }
 
+impl FromStr for Float {
+    type Err = anyhow::Error;
+    fn from_str(float: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid floating point number `{}`", float);
+
+        let (mantissa, exponent) = float.split_once(['e', 'E']).unwrap_or((float, "0"));
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+        let digits = format!("{}{}", integer, fraction)
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+        let exponent = exponent.parse::<i32>().map_err(|_| invalid())? - fraction.len() as i32;
+
+        Ok(Float { digits, exponent })
+    }
+}
+
 fn parse(line: &str) -> Option<Token> {
     match &*line.to_ascii_lowercase() {
         "started scanner test." => return Some(Token::Start),
@@ -141,7 +534,34 @@ fn parse(line: &str) -> Option<Token> {
     }
 
     let (_, line) = line.split_once(':')?;
-    let (r#type, line) = line.trim_start().split_once(' ')?;
+    let line = line.trim_start();
+    let (r#type, line) = line.split_once(' ').unwrap_or((line, ""));
+
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let sentinel = match (r#type, fields.as_slice()) {
+        (SENTINEL_TYPE, _) => Some(Sentinel::TokenType),
+        ("0" | "1" | "2", ["which:", SENTINEL_INTEGER, ..]) => Some(Sentinel::Which),
+        ("5", ["type:", SENTINEL_TYPE, ..]) => Some(Sentinel::DataType),
+        ("5", ["type:", "0", SENTINEL_INTEGER, ..]) => Some(Sentinel::Integer),
+        ("3" | "4", _) => {
+            // Values are at most 15 characters, so a longer one ran past the
+            // end of an unterminated `stringval`
+            let value = line.trim_start().trim_start_matches("value:").trim();
+            if value.len() >= 16 && value.bytes().all(|byte| byte == b'#') {
+                Some(Sentinel::String)
+            } else if value.len() > 15 && value.contains('#') {
+                Some(Sentinel::Unterminated)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(sentinel) = sentinel {
+        return Some(Token::Uninitialized(sentinel));
+    }
+
     match r#type {
         "0" => line
             .split_whitespace()
@@ -173,21 +593,84 @@ fn parse(line: &str) -> Option<Token> {
                     .ok()
                     .map(Number::Integer)
                     .map(Token::Number),
-                "1" => {
-                    let value = iter.next()?;
-                    value.parse::<f32>().ok()?;
-                    let (mantissa, exponent) = value.split_once('e')?;
-                    Some(Token::Number(Number::Float {
-                        mantissa: mantissa.parse().ok()?,
-                        exponent: exponent.parse().ok()?,
-                    }))
-                }
+                "1" => iter
+                    .next()?
+                    .parse()
+                    .ok()
+                    .map(Number::Float)
+                    .map(Token::Number),
                 _ => None,
             }
         }
-        r#type => {
-            println!("Unknown token type: {}", r#type);
-            None
+        _ => None,
+    }
+}
+
+/// What's wrong with a `line` that `parse` rejected, if it's a token with an
+/// unknown type.
+fn unknown(line: &str) -> Option<String> {
+    let (label, line) = line.split_once(':')?;
+    let r#type = line.split_whitespace().next()?;
+
+    match label.trim_end().ends_with("tokentype") {
+        true if !matches!(r#type, "0" | "1" | "2" | "3" | "4" | "5") => {
+            Some(format!("unknown token type {}", r#type))
         }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lex;
+    use crate::run::Exit;
+
+    use difference::Difference;
+
+    use super::compare;
+    use super::Tolerance;
+    use super::RUBRIC;
+
+    fn score(actual: &str) -> u32 {
+        let expecteds = [
+            "Started scanner test.",
+            "tokentype:  5  type:     1 3.141593e+00",
+        ];
+        let actuals = ["Started scanner test.", actual];
+        let differences = compare(&expecteds, &actuals, Tolerance::LastDigit).unwrap();
+        RUBRIC
+            .score(&lex::mismatches(&differences, Exit::Success))
+            .earned
+    }
+
+    #[test]
+    fn last_digit() {
+        assert_eq!(score("tokentype:  5  type:     1 3.141593e+00"), 1);
+        assert_eq!(score("tokentype:  5  type:     1 3.141594e+00"), 1);
+        assert_eq!(score("tokentype:  5  type:     1 3.141592e+00"), 1);
+        assert_eq!(score("tokentype:  5  type:     1 3.141595e+00"), 0);
+    }
+
+    #[test]
+    fn unknown_token_type() {
+        let expecteds = ["Started scanner test.", "tokentype:  3 value:  x"];
+        let actuals = [
+            "Started scanner test.",
+            "tokentype:  7 value:  x",
+            "tokentype:  9 value:  y",
+        ];
+        let differences = compare(&expecteds, &actuals, Tolerance::LastDigit).unwrap();
+        assert_eq!(
+            differences[1..],
+            [
+                Difference::Rem(String::from("tokentype:  3 value:  x")),
+                Difference::Add(String::from(
+                    "tokentype:  7 value:  x (expected identifier `x`, got unknown token type 7)"
+                )),
+                Difference::Add(String::from(
+                    "tokentype:  9 value:  y (unknown token type 9)"
+                )),
+            ]
+        );
     }
 }
@@ -1,27 +1,36 @@
-use std::iter;
 use std::path::Path;
-use std::str;
 
 use include_dir::include_dir;
 use include_dir::Dir;
 
+use crate::build::Recipe;
 use crate::lex;
+use crate::report::Report;
+use crate::run::Options;
+use crate::score::Rubric;
 
 static EXPECTEDS: Dir = include_dir!("$CARGO_MANIFEST_DIR/sample_p2");
 
-pub fn grade<P: AsRef<Path>>(workspace: P, verbose: bool) -> anyhow::Result<()> {
-    let different = move |expecteds: &mut iter::Peekable<str::Split<char>>,
-                          actuals: &mut iter::Peekable<str::Split<char>>| {
-        let expected = expecteds
-            .next()
-            .expect("[INTERNAL ERROR]: caller guarantees non-None");
+/// Graded the same as p1 (see `rubrics/p2.md`).
+const RUBRIC: Rubric = Rubric::all(1);
 
-        let actual = actuals
-            .next()
-            .expect("[INTERNAL ERROR]: caller guarantees non-None");
+const RECIPES: &[Recipe] = &[Recipe {
+    target: "lexer",
+    binary: "lexer",
+    requires: &["lexan.l"],
+    patches: &[],
+}];
 
-        Ok(expected != actual)
+pub fn total() -> u32 {
+    lex::total(RUBRIC)
+}
+
+pub fn grade<P: AsRef<Path>>(workspace: P, options: &Options) -> anyhow::Result<Report> {
+    let compare = |expecteds: &[&str], actuals: &[&str]| {
+        Ok(lex::align(expecteds, actuals, |i, j| {
+            expecteds[i] == actuals[j]
+        }))
     };
 
-    lex::grade(workspace, verbose, "lexer", &EXPECTEDS, different)
+    lex::grade(workspace, RECIPES, &EXPECTEDS, RUBRIC, options, compare)
 }
@@ -1,25 +1,41 @@
 use std::path::Path;
 
 use crate::parse;
+use crate::report::Report;
+use crate::run::Options;
+use crate::score::Mismatch;
+use crate::score::Rubric;
 
-pub fn grade<P: AsRef<Path>>(workspace: P, verbose: bool) -> anyhow::Result<()> {
+/// From `rubrics/p3.md`: -5 per incorrect symbol, -4 per incorrect tree line.
+const RUBRIC: Rubric = Rubric {
+    points: 100,
+    deductions: &[(Mismatch::Symbol, 5), (Mismatch::Line, 4)],
+    scale: None,
+};
+
+pub fn total() -> u32 {
+    RUBRIC.total()
+}
+
+pub fn grade<P: AsRef<Path>>(workspace: P, options: &Options) -> anyhow::Result<Report> {
     parse::grade(
         workspace,
-        verbose,
         &[parse::Test {
             path: Path::new("cs375_minimal/trivb.pas"),
+            rubric: RUBRIC,
             input: include_str!(concat!(
                 env!("CARGO_MANIFEST_DIR"),
                 "/cs375_minimal/trivb.pas"
             )),
-            table: include_str!(concat!(
+            table: Some(include_str!(concat!(
                 env!("CARGO_MANIFEST_DIR"),
                 "/sample_symtab/trivb_table.txt"
-            )),
-            tree: include_str!(concat!(
+            ))),
+            trees: vec![include_str!(concat!(
                 env!("CARGO_MANIFEST_DIR"),
                 "/sample_trees/trivb.sample"
-            )),
+            ))],
         }],
+        options,
     )
 }
@@ -1,25 +1,42 @@
 use std::path::Path;
 
 use crate::parse;
+use crate::report::Report;
+use crate::run::Options;
+use crate::score::Mismatch;
+use crate::score::Rubric;
 
-pub fn grade<P: AsRef<Path>>(workspace: P, verbose: bool) -> anyhow::Result<()> {
-    parse::grade(
-        workspace,
-        verbose,
-        &[parse::Test {
-            path: Path::new("cs375_minimal/graph1i.pas"),
-            input: include_str!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/cs375_minimal/graph1i.pas"
-            )),
-            table: include_str!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/sample_symtab/graph1_table.txt"
-            )),
-            tree: include_str!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/sample_trees/graph1i.sample"
-            )),
-        }],
-    )
+/// From `rubrics/p4.md`, which takes 2 points for most mistakes.
+pub(crate) const RUBRIC: Rubric = Rubric {
+    points: 100,
+    deductions: &[(Mismatch::Symbol, 2), (Mismatch::Line, 2)],
+    scale: None,
+};
+
+pub fn total() -> u32 {
+    RUBRIC.total()
+}
+
+pub fn grade<P: AsRef<Path>>(workspace: P, options: &Options) -> anyhow::Result<Report> {
+    parse::grade(workspace, &[test(RUBRIC)], options)
+}
+
+/// Parse `graph1i.pas`, which is also regraded (at a lower weight) in p5.
+pub(crate) fn test(rubric: Rubric) -> parse::Test<'static> {
+    parse::Test {
+        path: Path::new("cs375_minimal/graph1i.pas"),
+        rubric,
+        input: include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/cs375_minimal/graph1i.pas"
+        )),
+        table: Some(include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/sample_symtab/graph1_table.txt"
+        ))),
+        trees: vec![include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/sample_trees/graph1i.sample"
+        ))],
+    }
 }
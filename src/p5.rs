@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use include_dir::include_dir;
+use include_dir::Dir;
+
+use crate::p4;
+use crate::parse;
+use crate::report::Report;
+use crate::run::Options;
+use crate::score::Mismatch;
+use crate::score::Rubric;
+
+static TESTS: Dir = include_dir!("$CARGO_MANIFEST_DIR/test_p5");
+static EXPECTEDS: Dir = include_dir!("$CARGO_MANIFEST_DIR/sample_p5");
+
+/// Points for each unit test in `test_p5`, from `rubrics/p5.md`.
+const POINTS: [u32; 16] = [4, 5, 8, 8, 4, 4, 5, 5, 8, 6, 4, 4, 5, 6, 6, 8];
+
+/// The first few unit tests check the symbol table only.
+const TABLES: usize = 4;
+
+/// `graph1i.pas` is regraded using the p4 samples and rubric, scaled to 10 points:
+/// deductions are taken out of 100 as in p4, then the score is divided by 10.
+const GRAPH1I: Rubric = p4::RUBRIC.scaled(10);
+
+/// `rubrics/p5.md` gives partial points when only part of a unit test's
+/// output is correct, without saying how many, so take the smallest whole
+/// deduction for each mistake.
+const DEDUCTIONS: &[(Mismatch, u32)] = &[(Mismatch::Symbol, 1), (Mismatch::Line, 1)];
+
+/// Points for every test, including the scaled `graph1i.pas`.
+pub fn total() -> u32 {
+    POINTS.iter().sum::<u32>() + GRAPH1I.total()
+}
+
+pub fn grade<P: AsRef<Path>>(workspace: P, options: &Options) -> anyhow::Result<Report> {
+    let mut tests = TESTS.files().collect::<Vec<_>>();
+
+    tests.sort_by_key(|file| file.path().file_name().unwrap());
+
+    let tests = tests
+        .into_iter()
+        .zip(POINTS)
+        .enumerate()
+        .map(|(index, (test, points))| {
+            let stem = test.path().file_stem().unwrap().to_string_lossy();
+            let sample = |suffix: &str| {
+                EXPECTEDS
+                    .get_file(format!("{}{}.sample", stem, suffix))
+                    .map(|file| file.contents_utf8().unwrap_or_default())
+            };
+
+            let expected = sample("").expect("[INTERNAL ERROR]: missing p5 sample");
+
+            let (table, trees) = if index < TABLES {
+                // Samples include the `Symbol table level 1` header
+                let (_, table) = expected.split_once('\n').unwrap_or_default();
+                (Some(table), Vec::new())
+            } else {
+                // Some tests have an alternate sample, e.g. `test040.sample`
+                (
+                    None,
+                    Some(expected).into_iter().chain(sample("0")).collect(),
+                )
+            };
+
+            parse::Test {
+                path: test.path(),
+                rubric: Rubric {
+                    points,
+                    deductions: DEDUCTIONS,
+                    scale: None,
+                },
+                input: test.contents_utf8().unwrap_or_default(),
+                table,
+                trees,
+            }
+        })
+        .chain(Some(p4::test(GRAPH1I)))
+        .collect::<Vec<_>>();
+
+    parse::grade(workspace, &tests, options)
+}
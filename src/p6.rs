@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use difference::Changeset;
+use difference::Difference;
+use include_dir::include_dir;
+use include_dir::Dir;
+
+use crate::asm;
+use crate::build::Builder;
+use crate::build::Edit;
+use crate::build::Patch;
+use crate::build::Recipe;
+use crate::execute::Bench;
+use crate::execute::Driver;
+use crate::report;
+use crate::report::Behavior;
+use crate::report::Report;
+use crate::run;
+use crate::run::Exit;
+use crate::run::Options;
+use crate::sandbox::Sandbox;
+use crate::score::Mismatch;
+use crate::score::Rubric;
+
+static TESTS: Dir = include_dir!("$CARGO_MANIFEST_DIR/test_p6");
+static EXPECTEDS: Dir = include_dir!("$CARGO_MANIFEST_DIR/sample_p6");
+
+/// Points for each unit test in `test_p6`, from `rubrics/p6.md`.
+const POINTS: [u32; 31] = [
+    2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3, 2, 2, 2, 4, 4, 4, 2, 3, 3, 3, 3, 4, 5, 5, 6, 6, 5, 5, 1, 1,
+];
+
+/// A Bison parser, or else a hand-written one in C, as in `codegen_autograder.sh`.
+/// Like that script, the Bison parser is patched to canonicalize expressions
+/// before `gencode`, and to call `gencode` at all if it was commented out.
+const RECIPES: &[Recipe] = &[
+    Recipe {
+        target: "compiler",
+        binary: "compiler",
+        requires: &["parse.y"],
+        patches: &[
+            Patch {
+                file: "parse.y",
+                edit: Edit::Uncomment("gencode"),
+            },
+            Patch {
+                file: "parse.y",
+                edit: Edit::Before {
+                    function: "gencode",
+                    text: "exprCanonicalization(parseresult); ",
+                },
+            },
+            Patch {
+                file: "parse.y",
+                edit: Edit::CommentOut("yydebug"),
+            },
+        ],
+    },
+    Recipe {
+        target: "compc",
+        binary: "compc",
+        requires: &["parsc.c"],
+        patches: &[],
+    },
+];
+
+const BEGIN: &str = "begin Your code";
+const END: &str = "begin Epilogue code";
+
+enum Outcome {
+    /// Output matched the sample with this file name.
+    Pass(String),
+    Fail(Vec<Difference>),
+
+    /// The compiler was killed, so its output is incomplete.
+    Killed,
+}
+
+pub fn total() -> u32 {
+    POINTS.iter().sum()
+}
+
+pub fn grade<P: AsRef<Path>>(
+    workspace: P,
+    only: Option<usize>,
+    driver: Option<&Driver>,
+    options: &Options,
+) -> anyhow::Result<Report> {
+    let mut report = Report::new(workspace.as_ref());
+    let sandbox = Sandbox::new(workspace.as_ref().canonicalize()?, options)?;
+
+    let mut tests = TESTS.files().collect::<Vec<_>>();
+
+    tests.sort_by_key(|file| file.path().file_name().unwrap());
+
+    let mut tests = tests
+        .into_iter()
+        .zip(POINTS)
+        .enumerate()
+        .collect::<Vec<_>>();
+
+    if let Some(only) = only {
+        tests.retain(|(index, _)| *index == only);
+        if tests.is_empty() {
+            return Err(anyhow!(
+                "Unit test number {} out of range [0,{}]",
+                only,
+                POINTS.len() - 1
+            ));
+        }
+    }
+
+    let compiler = match Builder::new(&sandbox)?.build(RECIPES, &mut report) {
+        Ok(compiler) => compiler,
+        Err(failure) => {
+            let tests = tests.iter().map(|(_, (test, points))| {
+                let name = test.path().file_name().unwrap().to_string_lossy();
+                (name.into_owned(), *points)
+            });
+            report.not_built(&failure, tests);
+            return Ok(report);
+        }
+    };
+
+    // Without a bench, every test's behavior is unknown, but it can still be
+    // graded on its code
+    let (bench, unknown) = match driver.map(|driver| Bench::new(&sandbox, driver)) {
+        Some(Ok(bench)) => (Some(bench), None),
+        Some(Err(error)) => (None, Some(Behavior::Error(format!("{:#}", error)))),
+        None => (None, None),
+    };
+
+    for (_, (test, points)) in &tests {
+        let name = test.path().file_name().unwrap().to_string_lossy();
+        let rubric = Rubric::all(*points);
+        let graded = grade_test(&sandbox, &compiler.path, test, bench.as_ref(), options);
+        let (outcome, exit, behavior, stderr) = match graded {
+            Ok(result) => result,
+            Err(error) => {
+                report.push(report::Test::error(name.into_owned(), *points, &error));
+                continue;
+            }
+        };
+
+        let mut test = match outcome {
+            Outcome::Pass(sample) => {
+                let mut test =
+                    report::Test::new(name.into_owned(), rubric.score(&[]), Vec::new(), Some(exit));
+                test.note = Some(sample);
+                test
+            }
+            Outcome::Fail(differences) => report::Test::new(
+                name.into_owned(),
+                rubric.score(&[Mismatch::Line]),
+                differences,
+                Some(exit),
+            ),
+            Outcome::Killed => report::Test::new(
+                name.into_owned(),
+                rubric.score(&[Mismatch::Killed]),
+                Vec::new(),
+                Some(exit),
+            ),
+        };
+
+        test.behavior = behavior.or_else(|| unknown.clone());
+        test.stderr = stderr;
+        report.push(test);
+    }
+
+    Ok(report)
+}
+
+fn grade_test(
+    sandbox: &Sandbox,
+    compiler: &Path,
+    test: &include_dir::File,
+    bench: Option<&Bench>,
+    options: &Options,
+) -> anyhow::Result<(Outcome, Exit, Option<Behavior>, Option<String>)> {
+    let output = run::run(sandbox.command(compiler), test.contents(), options)?;
+    let exit = output.exit;
+
+    if exit.killed() {
+        return Ok((Outcome::Killed, exit, None, output.stderr()));
+    }
+
+    let stderr = output.stderr();
+    let output = String::from_utf8_lossy(&output.stdout);
+    let actual =
+        extract(&output).ok_or_else(|| anyhow!("No assembly code found (compiler {})", exit))?;
+
+    let stem = test.path().file_stem().unwrap().to_string_lossy();
+    let mut samples = ["", "0"]
+        .iter()
+        .map(|suffix| format!("{}{}.sample", stem, suffix))
+        .filter_map(|name| {
+            let expected = EXPECTEDS.get_file(&name)?.contents_utf8()?;
+            Some((name, expected, extract(expected)?))
+        })
+        .peekable();
+
+    let (_, sample, primary) = samples
+        .peek()
+        .cloned()
+        .ok_or_else(|| anyhow!("[INTERNAL ERROR]: missing p6 sample for {}", stem))?;
+
+    let behavior = bench.map(|bench| bench.compare(&output, sample, options));
+
+    for (name, _, expected) in samples {
+        if expected == actual {
+            return Ok((Outcome::Pass(name), exit, behavior, stderr));
+        }
+    }
+
+    Ok((
+        Outcome::Fail(Changeset::new(&primary, &actual, "\n").diffs),
+        exit,
+        behavior,
+        stderr,
+    ))
+}
+
+/// Extract the student-generated code between the `begin Your code` and
+/// `begin Epilogue code` markers, normalized by [`asm::normalize`] with the
+/// literal pool that follows.
+fn extract(output: &str) -> Option<String> {
+    let (_, code) = output.split_once(BEGIN)?;
+    let (_, code) = code.split_once('\n')?;
+    let (code, data) = code.find(END).map_or((code, ""), |end| code.split_at(end));
+
+    Some(asm::normalize(code, data).join("\n"))
+}
@@ -1,158 +1,532 @@
-use std::env;
-use std::io::Write as _;
+use std::fmt;
+use std::iter;
 use std::path::Path;
-use std::process::Command;
-use std::process::Stdio;
 
-use ansi_term::Color;
 use anyhow::anyhow;
 use anyhow::Context as _;
 use difference::Changeset;
 use difference::Difference;
 
+use crate::build::Builder;
+use crate::build::Recipe;
+use crate::report;
+use crate::report::Report;
+use crate::run;
+use crate::run::Options;
+use crate::run::Output;
+use crate::sandbox::Sandbox;
+use crate::score::Mismatch;
+use crate::score::Rubric;
+
+/// A Bison parser, or else a hand-written one in C.
+const RECIPES: &[Recipe] = &[
+    Recipe {
+        target: "parser",
+        binary: "parser",
+        requires: &["parse.y"],
+        patches: &[],
+    },
+    Recipe {
+        target: "parsec",
+        binary: "parsec",
+        requires: &["parsc.c"],
+        patches: &[],
+    },
+];
+
 pub(crate) struct Test<'a> {
     pub(crate) path: &'a Path,
+    pub(crate) rubric: Rubric,
     pub(crate) input: &'a str,
-    pub(crate) table: &'a str,
-    pub(crate) tree: &'a str,
+
+    /// Expected symbol table level 1, if this test checks it.
+    pub(crate) table: Option<&'a str>,
+
+    /// Expected parse trees: the first is the canonical sample, and
+    /// any others are accepted alternatives.
+    pub(crate) trees: Vec<&'a str>,
 }
 
 pub(crate) fn grade<P: AsRef<Path>>(
     workspace: P,
-    verbose: bool,
     tests: &[Test],
-) -> anyhow::Result<()> {
-    let student = workspace.as_ref().file_name().unwrap();
+    options: &Options,
+) -> anyhow::Result<Report> {
+    let mut report = Report::new(workspace.as_ref());
+    let sandbox = Sandbox::new(workspace.as_ref().canonicalize()?, options)?;
 
-    println!(
-        "[{}] grading in workspace {}...",
-        student.to_string_lossy(),
-        workspace.as_ref().display()
-    );
-
-    env::set_current_dir(&workspace)?;
-
-    let parser = match make("parser") {
-        Ok(()) => "./parser",
-        Err(_) => {
-            make("parsec")?;
-            "./parsec"
+    let parser = match Builder::new(&sandbox)?.build(RECIPES, &mut report) {
+        Ok(parser) => parser,
+        Err(failure) => {
+            let tests = tests.iter().map(|test| {
+                let name = test.path.file_name().unwrap().to_string_lossy();
+                (name.into_owned(), test.rubric.total())
+            });
+            report.not_built(&failure, tests);
+            return Ok(report);
         }
     };
 
-    let mut failures = 0;
-
     for test in tests {
-        let differences = grade_test(parser, test)
-            .with_context(|| anyhow!("Failed to grade test {}", test.path.display()))?;
         let name = test.path.file_name().unwrap().to_string_lossy();
-
-        match differences.is_empty() {
-            true if verbose => println!("- [{}]: pass", name),
-            true => (),
-            false => {
-                println!("- [{}]: fail", name);
-                failures += 1;
-            }
-        }
-
-        for difference in differences {
-            match difference {
-                difference::Difference::Same(_) => (),
-                difference::Difference::Add(added) => {
-                    print!("{}", Color::Green.paint("+ "));
-                    println!("{}", Color::Green.paint(added));
+        let (differences, mismatches, output) =
+            match grade_test(&sandbox, &parser.path, test, options) {
+                Ok(result) => result,
+                Err(error) => {
+                    report.push(report::Test::error(
+                        name.into_owned(),
+                        test.rubric.total(),
+                        &error,
+                    ));
+                    continue;
                 }
-                difference::Difference::Rem(removed) => {
-                    print!("{}", Color::Red.paint("- "));
-                    println!("{}", Color::Red.paint(removed));
-                }
-            }
-        }
+            };
+        let score = test.rubric.score(&mismatches);
+
+        let mut result =
+            report::Test::new(name.into_owned(), score, differences, Some(output.exit));
+        result.stderr = output.stderr();
+        report.push(result);
     }
 
-    println!(
-        "{}",
-        Color::Blue.paint(format!(
-            "[{}]: passed {} out of {}",
-            student.to_string_lossy(),
-            tests.len() - failures,
-            tests.len()
-        ))
-    );
-
-    Ok(())
+    Ok(report)
 }
 
 fn grade_test(
-    parser: &str,
+    sandbox: &Sandbox,
+    parser: &Path,
     Test {
         path: _,
+        rubric: _,
         input,
         table,
-        tree,
+        trees,
     }: &Test,
-) -> anyhow::Result<Vec<Difference>> {
-    let mut child = Command::new(parser)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+    options: &Options,
+) -> anyhow::Result<(Vec<Difference>, Vec<Mismatch>, Output)> {
+    let output = run::run(sandbox.command(parser), input.as_bytes(), options)?;
+    let exit = output.exit;
 
-    child.stdin.as_mut().unwrap().write_all(input.as_bytes())?;
+    // Output is incomplete, so there is no point in comparing it
+    if exit.killed() {
+        return Ok((Vec::new(), vec![Mismatch::Killed], output));
+    }
 
-    let stdout = child.wait_with_output()?.stdout;
-    let actual = String::from_utf8_lossy(&stdout);
+    let actual = String::from_utf8_lossy(&output.stdout);
     let mut differences = Vec::new();
+    let mut mismatches = Vec::new();
+
+    if let Some(table) = table {
+        let actual_table = actual
+            .find("Symbol table level 1")
+            .and_then(|index| actual.get(index..))
+            .ok_or_else(|| anyhow!("No symbol table found (parser {})", exit))?
+            .split_inclusive('\n')
+            .skip(1)
+            .take_while(|line| {
+                let line = line.trim();
+                !line.starts_with("(program")
+                    && line.starts_with(|char: char| char.is_numeric() || char == '(')
+            })
+            .collect::<String>();
+
+        for mut symbol in compare_tables(
+            &Symbol::parse_table(table),
+            &Symbol::parse_table(&actual_table),
+        ) {
+            differences.append(&mut symbol);
+            mismatches.push(Mismatch::Symbol);
+        }
+    }
+
+    if let Some((tree, alternatives)) = trees.split_first() {
+        let actual_tree = actual
+            .find("(program")
+            .and_then(|index| actual.get(index..))
+            .ok_or_else(|| anyhow!("No AST found (parser {})", exit))?
+            .trim();
 
-    let table = table
-        .split_inclusive('\n')
-        .map(|line| line.trim_start())
-        .map(|line| line.trim_start_matches(|char: char| char.is_numeric()))
-        .map(|line| line.trim_start())
-        .collect::<String>();
-
-    let actual_table = actual
-        .find("Symbol table level 1")
-        .and_then(|index| actual.get(index..))
-        .ok_or_else(|| anyhow!("No symbol table found"))?
-        .split_inclusive('\n')
-        .skip(1)
-        .take_while(|line| line.trim().starts_with(|char: char| char.is_numeric()))
-        .map(|line| line.trim_start())
-        .map(|line| line.trim_start_matches(|char: char| char.is_numeric()))
-        .map(|line| line.trim_start())
-        .collect::<String>();
-
-    differences.append(&mut Changeset::new(table.trim(), actual_table.trim(), "\n").diffs);
-
-    let actual_tree = actual
-        .find("(program")
-        .and_then(|index| actual.get(index..))
-        .ok_or_else(|| anyhow!("No AST found"))?
-        .trim();
-
-    differences.append(&mut Changeset::new(tree.trim(), actual_tree, "\n").diffs);
+        let parse = |tree: &str| {
+            Sexp::parse(tree)
+                .map(Sexp::normalize)
+                .with_context(|| anyhow!("[INTERNAL ERROR]: failed to parse expected tree"))
+        };
+
+        let expected = parse(tree)?;
+        let alternatives = alternatives
+            .iter()
+            .copied()
+            .map(parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        match Sexp::parse(actual_tree).map(Sexp::normalize) {
+            None => {
+                let mut changes = Changeset::new(tree.trim(), actual_tree, "\n").diffs;
+                let lines = changes
+                    .iter()
+                    .map(|difference| match difference {
+                        Difference::Same(_) => 0,
+                        Difference::Add(lines) | Difference::Rem(lines) => lines.lines().count(),
+                    })
+                    .sum();
+                differences.append(&mut changes);
+                mismatches.extend(iter::repeat_n(Mismatch::Line, lines));
+            }
+            Some(actual) if actual == expected || alternatives.contains(&actual) => (),
+            Some(actual) => {
+                let count = expected.count_differences(&actual);
+                let (path, expected, actual) = expected.first_difference(&actual, "");
+                differences.push(Difference::Rem(format!("{}: {}", path, expected)));
+                differences.push(Difference::Add(format!("{}: {}", path, actual)));
+                mismatches.extend(iter::repeat_n(Mismatch::Line, count));
+            }
+        }
+    }
 
     if differences
         .iter()
         .all(|difference| matches!(difference, Difference::Same(_)))
     {
-        Ok(Vec::new())
+        Ok((Vec::new(), Vec::new(), output))
     } else {
-        Ok(differences)
+        Ok((differences, mismatches, output))
+    }
+}
+
+/// Match symbols by name, ignoring order and addresses. Returns the
+/// differences for each incorrect symbol.
+fn compare_tables(expecteds: &[Symbol], actuals: &[Symbol]) -> Vec<Vec<Difference>> {
+    let mut differences = Vec::new();
+
+    for expected in expecteds {
+        match actuals.iter().find(|actual| actual.name == expected.name) {
+            Some(actual) if actual == expected => (),
+            Some(actual) => differences.push(vec![
+                Difference::Rem(expected.to_string()),
+                Difference::Add(actual.to_string()),
+            ]),
+            None => differences.push(vec![Difference::Rem(expected.to_string())]),
+        }
+    }
+
+    for actual in actuals {
+        if !expecteds
+            .iter()
+            .any(|expected| expected.name == actual.name)
+        {
+            differences.push(vec![Difference::Add(actual.to_string())]);
+        }
+    }
+
+    differences
+}
+
+/// An entry of the symbol table printed by `printstlevel`, e.g.
+///
+/// ```text
+///  16776080          ac  VAR    0 typ 16769808  lvl  1  siz   160  off    16
+/// (ARRAY   1 ..  10 (RECORD (re real)
+///                           (im real)))
+/// ```
+#[derive(Debug, PartialEq)]
+struct Symbol {
+    name: String,
+    kind: String,
+    basicdt: Option<String>,
+    r#type: Type,
+    level: Option<String>,
+    size: Option<String>,
+    offset: Option<String>,
+    value: Option<String>,
+
+    /// Pretty-printed type following the entry, with whitespace normalized.
+    pretty: Option<String>,
+}
+
+/// The `typ` field of a symbol, with addresses resolved.
+#[derive(Debug, PartialEq)]
+enum Type {
+    /// Basic type printed by name, e.g. `INTEGER` or `real`.
+    Basic(String),
+
+    /// Shares its type with the `TYPE` symbol of this name.
+    Named(String),
+
+    /// Points at the symbol of this name itself, rather than its type.
+    Symbol(String),
+
+    /// Unnamed type, compared structurally using the pretty-printed type.
+    Anonymous,
+}
+
+impl Symbol {
+    fn parse_table(table: &str) -> Vec<Symbol> {
+        let mut entries = Vec::<(&str, Vec<&str>, Vec<&str>)>::new();
+
+        for line in table.lines() {
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            match tokens.as_slice() {
+                [address, _, kind, ..]
+                    if address.parse::<u64>().is_ok()
+                        && kind.chars().all(|char| char.is_ascii_uppercase()) =>
+                {
+                    entries.push((address, tokens, Vec::new()));
+                }
+                [] => (),
+                _ => {
+                    if let Some((_, _, pretty)) = entries.last_mut() {
+                        pretty.push(line);
+                    }
+                }
+            }
+        }
+
+        let types = entries
+            .iter()
+            .filter(|(_, tokens, _)| tokens[2] == "TYPE")
+            .filter_map(|(_, tokens, _)| {
+                let index = tokens.iter().position(|token| *token == "typ")?;
+                Some((tokens.get(index + 1)?.to_string(), tokens[1].to_string()))
+            })
+            .collect::<Vec<_>>();
+
+        entries
+            .iter()
+            .map(|(_, tokens, pretty)| {
+                let field = |name: &str| {
+                    let index = tokens.iter().position(|token| *token == name)?;
+                    tokens.get(index + 1).map(|token| token.to_string())
+                };
+
+                let r#type = match field("typ") {
+                    None => Type::Anonymous,
+                    Some(typ) if typ.parse::<u64>().is_err() => Type::Basic(typ),
+                    Some(typ) => types
+                        .iter()
+                        .find(|(address, _)| *address == typ)
+                        .map(|(_, name)| Type::Named(name.clone()))
+                        .or_else(|| {
+                            entries
+                                .iter()
+                                .find(|(address, _, _)| *address == typ)
+                                .map(|(_, tokens, _)| Type::Symbol(tokens[1].to_string()))
+                        })
+                        .unwrap_or(Type::Anonymous),
+                };
+
+                Symbol {
+                    name: tokens[1].to_string(),
+                    kind: tokens[2].to_string(),
+                    basicdt: match tokens.get(3) {
+                        Some(token) if *token != "typ" => Some(token.to_string()),
+                        _ => None,
+                    },
+                    r#type,
+                    level: field("lvl"),
+                    size: field("siz"),
+                    offset: field("off"),
+                    value: field("val"),
+                    pretty: match pretty.is_empty() {
+                        true => None,
+                        false => Some(
+                            pretty
+                                .iter()
+                                .flat_map(|line| line.split_whitespace())
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                        ),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {}", self.name, self.kind)?;
+
+        if let Some(basicdt) = &self.basicdt {
+            write!(fmt, " {}", basicdt)?;
+        }
+
+        match &self.r#type {
+            Type::Basic(name) => write!(fmt, " typ {}", name)?,
+            Type::Named(name) => write!(fmt, " typ <type of {}>", name)?,
+            Type::Symbol(name) => write!(fmt, " typ <symbol {}>", name)?,
+            Type::Anonymous => write!(fmt, " typ <anonymous>")?,
+        }
+
+        for (name, field) in [
+            ("lvl", &self.level),
+            ("siz", &self.size),
+            ("off", &self.offset),
+            ("val", &self.value),
+        ] {
+            if let Some(field) = field {
+                write!(fmt, " {} {}", name, field)?;
+            }
+        }
+
+        if let Some(pretty) = &self.pretty {
+            write!(fmt, "\n{}", pretty)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An s-expression from the pretty-printed parse tree.
+#[derive(Clone, Debug, PartialEq)]
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    /// Parse the first s-expression in `text`, ignoring anything after it.
+    fn parse(text: &str) -> Option<Sexp> {
+        let mut stack = vec![Vec::new()];
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((start, char)) = chars.next() {
+            match char {
+                '(' => stack.push(Vec::new()),
+                ')' => {
+                    let list = Sexp::List(stack.pop()?);
+                    let parent = stack.last_mut()?;
+                    parent.push(list);
+                }
+                char if char.is_whitespace() => continue,
+                _ => {
+                    // Quoted strings such as `'x = '` may contain spaces or parentheses
+                    let quoted = char == '\'';
+                    let mut end = start + char.len_utf8();
+                    while let Some((index, char)) = chars.peek().copied() {
+                        match char {
+                            '\'' if quoted => {
+                                chars.next();
+                                end = index + 1;
+                                break;
+                            }
+                            _ if quoted => (),
+                            '(' | ')' => break,
+                            char if char.is_whitespace() => break,
+                            _ => (),
+                        }
+                        chars.next();
+                        end = index + char.len_utf8();
+                    }
+                    stack
+                        .last_mut()?
+                        .push(Sexp::Atom(text[start..end].to_string()));
+                }
+            }
+
+            if let [root] = stack.as_slice() {
+                if let [sexp] = root.as_slice() {
+                    return Some(sexp.clone());
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Splice `progn` lists directly nested in another `progn` into their parent,
+    /// mirroring `removeExtraProgn` in `pprint.c`.
+    fn normalize(self) -> Sexp {
+        let list = match self {
+            Sexp::Atom(_) => return self,
+            Sexp::List(list) => list,
+        };
+
+        let progn = matches!(list.first(), Some(Sexp::Atom(head)) if head == "progn");
+        let mut normalized = Vec::with_capacity(list.len());
+
+        for (index, sexp) in list.into_iter().enumerate() {
+            match sexp.normalize() {
+                Sexp::List(inner)
+                    if progn
+                        && index > 0
+                        && matches!(inner.first(), Some(Sexp::Atom(head)) if head == "progn") =>
+                {
+                    normalized.extend(inner.into_iter().skip(1));
+                }
+                sexp => normalized.push(sexp),
+            }
+        }
+
+        Sexp::List(normalized)
+    }
+
+    fn head(&self) -> Option<&str> {
+        match self {
+            Sexp::List(list) => match list.first() {
+                Some(Sexp::Atom(head)) => Some(head),
+                _ => None,
+            },
+            Sexp::Atom(_) => None,
+        }
+    }
+
+    /// Count the smallest differing subtrees, which roughly correspond
+    /// to lines of the sample.
+    fn count_differences(&self, other: &Sexp) -> usize {
+        match (self, other) {
+            (Sexp::List(left), Sexp::List(right))
+                if left.len() == right.len() && self.head() == other.head() =>
+            {
+                left.iter()
+                    .zip(right)
+                    .map(|(left, right)| left.count_differences(right))
+                    .sum()
+            }
+            _ if self == other => 0,
+            _ => 1,
+        }
+    }
+
+    /// Find the first differing subtree, along with a description of where it is.
+    fn first_difference<'a>(&'a self, other: &'a Sexp, path: &str) -> (String, &'a Sexp, &'a Sexp) {
+        match (self, other) {
+            (Sexp::List(left), Sexp::List(right))
+                if left.len() == right.len() && self.head() == other.head() =>
+            {
+                let head = self.head().unwrap_or("list");
+                left.iter()
+                    .zip(right)
+                    .enumerate()
+                    .find(|(_, (left, right))| left != right)
+                    .map(|(index, (left, right))| {
+                        let path = match path.is_empty() {
+                            true => format!("operand {} of `{}`", index, head),
+                            false => format!("operand {} of `{}` in {}", index, head, path),
+                        };
+                        left.first_difference(right, &path)
+                    })
+                    .unwrap_or_else(|| (path.to_string(), self, other))
+            }
+            _ if path.is_empty() => (String::from("tree"), self, other),
+            _ => (path.to_string(), self, other),
+        }
     }
 }
 
-fn make(rule: &str) -> anyhow::Result<()> {
-    Command::new("make")
-        .arg(rule)
-        .spawn()
-        .context("Could not execute `make`")?
-        .wait()
-        .map_err(anyhow::Error::new)
-        .and_then(|status| match status.success() {
-            true => Ok(()),
-            false => Err(anyhow!(status)),
-        })
+impl fmt::Display for Sexp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexp::Atom(atom) => write!(fmt, "{}", atom),
+            Sexp::List(list) => {
+                write!(fmt, "(")?;
+                for (index, sexp) in list.iter().enumerate() {
+                    if index > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{}", sexp)?;
+                }
+                write!(fmt, ")")
+            }
+        }
+    }
 }
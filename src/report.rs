@@ -0,0 +1,451 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use ansi_term::Color;
+use difference::Difference;
+use serde::Serialize;
+
+use crate::build;
+use crate::build::Failure;
+use crate::late::Late;
+use crate::metadata::Fallback;
+use crate::run::Exit;
+use crate::score::Score;
+
+/// Grading results for a single workspace.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub student: String,
+    pub workspace: PathBuf,
+    pub score: Score,
+    pub tests: Vec<Test>,
+
+    /// Error that prevented (some of) the tests from running, e.g. a failed build.
+    pub error: Option<String>,
+
+    /// Files copied from an earlier project because the student didn't submit them.
+    pub fallbacks: Vec<Fallback>,
+
+    /// Which build recipe was used, and why.
+    pub build: Option<String>,
+
+    /// Edits made (or attempted) on a copy of the student's sources before building.
+    pub patches: Vec<Patched>,
+
+    /// Late penalty, already deducted from `score`.
+    pub late: Option<Late>,
+}
+
+/// An edit to a student's source, e.g. to call the code generator.
+#[derive(Debug, Serialize)]
+pub struct Patched {
+    pub edit: String,
+    pub status: PatchStatus,
+}
+
+/// What came of an edit, judged by whether the source does what it was
+/// meant to afterwards, e.g. calls `gencode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchStatus {
+    Applied,
+
+    /// The source already did what the edit was meant to.
+    Unneeded,
+
+    /// The source still doesn't do what the edit was meant to.
+    Failed,
+}
+
+/// Grading results for a single test.
+#[derive(Debug, Serialize)]
+pub struct Test {
+    pub name: String,
+    pub status: Status,
+    pub score: Score,
+    pub differences: Vec<Line>,
+
+    /// How the student binary terminated, if it was run.
+    pub exit: Option<Exit>,
+
+    /// Additional information, e.g. which sample variant was matched.
+    pub note: Option<String>,
+
+    /// Why the test could not be run, if it couldn't.
+    pub error: Option<String>,
+
+    /// Input the student binary was run on, kept for failing tests so the
+    /// differences can be read against it.
+    pub source: Option<String>,
+
+    /// What the student binary wrote to stderr, if anything.
+    pub stderr: Option<String>,
+
+    /// How the student's generated code ran compared to the sample's, if
+    /// it was executed.
+    pub behavior: Option<Behavior>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+
+    /// Wrong output, or a timeout.
+    Fail,
+
+    /// The test could not be run, or its output could not be compared.
+    Error,
+}
+
+/// Behavioral verdict for generated code, reported alongside the textual one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "reason", rename_all = "lowercase")]
+pub enum Behavior {
+    /// Printed the same output and exited the same way as the sample.
+    Same,
+    Different(String),
+
+    /// The sample itself could not be run, so there is nothing to compare.
+    Error(String),
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Behavior::Same => write!(fmt, "behaves like the sample"),
+            Behavior::Different(reason) => write!(fmt, "behaves differently: {}", reason),
+            Behavior::Error(reason) => write!(fmt, "behavior unknown: {}", reason),
+        }
+    }
+}
+
+/// A line present only in the sample (`Rem`) or only in the student output (`Add`).
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "text", rename_all = "lowercase")]
+pub enum Line {
+    Add(String),
+    Rem(String),
+}
+
+impl Report {
+    pub fn new<P: Into<PathBuf>>(workspace: P) -> Self {
+        let workspace = workspace.into();
+        Report {
+            student: workspace
+                .file_name()
+                .unwrap_or(workspace.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+            workspace,
+            score: Score::default(),
+            tests: Vec::new(),
+            error: None,
+            fallbacks: Vec::new(),
+            build: None,
+            patches: Vec::new(),
+            late: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, test: Test) {
+        self.score += test.score;
+        self.tests.push(test);
+    }
+
+    /// Record that the build failed, and fail each of `tests` (by name and
+    /// points) without running it.
+    pub(crate) fn not_built<I>(&mut self, failure: &Failure, tests: I)
+    where
+        I: IntoIterator<Item = (String, u32)>,
+    {
+        self.error = Some(format!(
+            "Could not build (full log in {}): {}",
+            build::LOG,
+            failure
+        ));
+
+        for (name, total) in tests {
+            self.push(Test::error(name, total, &anyhow::anyhow!("not built")));
+        }
+    }
+
+    pub fn passed(&self) -> usize {
+        self.count(Status::Pass)
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.tests
+            .iter()
+            .filter(|test| test.status == status)
+            .count()
+    }
+
+    /// Write a human-readable report.
+    pub fn write<W: io::Write>(&self, mut writer: W, verbose: bool) -> io::Result<()> {
+        writeln!(
+            writer,
+            "[{}] grading in workspace {}...",
+            self.student,
+            self.workspace.display()
+        )?;
+
+        for fallback in &self.fallbacks {
+            writeln!(
+                writer,
+                "Using {} from an earlier project ({})",
+                fallback.name, fallback.source
+            )?;
+        }
+
+        if let Some(build) = &self.build {
+            writeln!(writer, "{}", build)?;
+        }
+
+        for patch in &self.patches {
+            match patch.status {
+                PatchStatus::Applied => {
+                    writeln!(writer, "Patched a copy of the sources to {}", patch.edit)?
+                }
+                PatchStatus::Unneeded => (),
+                PatchStatus::Failed => {
+                    writeln!(writer, "Could not patch the sources to {}", patch.edit)?
+                }
+            }
+        }
+
+        if let Some(late) = &self.late {
+            writeln!(
+                writer,
+                "Late by {} day(s): {} points deducted",
+                late.days, late.penalty
+            )?;
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(writer, "{}", error)?;
+        }
+
+        for test in &self.tests {
+            let note = test
+                .exit
+                .filter(|exit| *exit != Exit::Success)
+                .map(|exit| exit.to_string())
+                .into_iter()
+                .chain(test.note.clone())
+                .chain(test.behavior.as_ref().map(Behavior::to_string))
+                .map(|note| format!(" ({})", note))
+                .collect::<String>();
+            let printed = verbose || test.status != Status::Pass;
+
+            match test.status {
+                Status::Pass if verbose => {
+                    writeln!(writer, "- [{}] ({}): pass{}", test.name, test.score, note)?
+                }
+                Status::Pass => (),
+                Status::Fail => {
+                    writeln!(writer, "- [{}] ({}): fail{}", test.name, test.score, note)?
+                }
+                Status::Error => writeln!(
+                    writer,
+                    "- [{}] ({}): could not run: {}",
+                    test.name,
+                    test.score,
+                    test.error.as_deref().unwrap_or_default()
+                )?,
+            }
+
+            if let Some(source) = &test.source {
+                for (number, line) in source.trim_end().lines().enumerate() {
+                    writeln!(writer, "  {:>3} | {}", number + 1, line)?;
+                }
+            }
+
+            if let Some(stderr) = test.stderr.as_ref().filter(|_| printed) {
+                for line in stderr.trim_end().lines() {
+                    writeln!(writer, "  stderr: {}", line)?;
+                }
+            }
+
+            for line in &test.differences {
+                match line {
+                    Line::Add(added) => {
+                        write!(writer, "{}", Color::Green.paint("+ "))?;
+                        writeln!(writer, "{}", Color::Green.paint(added))?;
+                    }
+                    Line::Rem(removed) => {
+                        write!(writer, "{}", Color::Red.paint("- "))?;
+                        writeln!(writer, "{}", Color::Red.paint(removed))?;
+                    }
+                }
+            }
+        }
+
+        let breakdown = match (self.count(Status::Fail), self.count(Status::Error)) {
+            (_, 0) => String::new(),
+            (failed, errors) => format!(" ({} wrong output, {} could not run)", failed, errors),
+        };
+
+        writeln!(
+            writer,
+            "{}",
+            Color::Blue.paint(format!(
+                "[{}]: passed {} out of {}{}, scoring {} points",
+                self.student,
+                self.passed(),
+                self.tests.len(),
+                breakdown,
+                self.score,
+            ))
+        )
+    }
+}
+
+impl Test {
+    pub(crate) fn new(
+        name: String,
+        score: Score,
+        differences: Vec<Difference>,
+        exit: Option<Exit>,
+    ) -> Self {
+        let differences = differences
+            .into_iter()
+            .filter_map(|difference| match difference {
+                Difference::Same(_) => None,
+                Difference::Add(added) => Some(Line::Add(added)),
+                Difference::Rem(removed) => Some(Line::Rem(removed)),
+            })
+            .collect::<Vec<_>>();
+
+        Test {
+            name,
+            status: match (differences.is_empty(), exit) {
+                (true, Some(exit)) if exit.killed() => Status::Fail,
+                (false, _) => Status::Fail,
+                (true, _) => Status::Pass,
+            },
+            score,
+            differences,
+            exit,
+            note: None,
+            error: None,
+            source: None,
+            stderr: None,
+            behavior: None,
+        }
+    }
+
+    /// A test that could not be run, worth none of its `total` points.
+    pub(crate) fn error(name: String, total: u32, error: &anyhow::Error) -> Self {
+        Test {
+            name,
+            status: Status::Error,
+            score: Score { earned: 0, total },
+            differences: Vec::new(),
+            exit: None,
+            note: None,
+            error: Some(format!("{:#}", error)),
+            source: None,
+            stderr: None,
+            behavior: None,
+        }
+    }
+}
+
+/// Write all reports as a single JSON document.
+pub fn write_json<W: io::Write>(writer: W, reports: &[Report]) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(writer, reports)?;
+    Ok(())
+}
+
+/// Write one CSV record per student and test. Workspace-level errors are
+/// repeated on each test, or recorded in a row with an empty test name if
+/// no tests ran.
+pub fn write_csv<W: io::Write>(writer: W, reports: &[Report]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+
+    writer.write_record([
+        "student",
+        "test",
+        "status",
+        "earned",
+        "total",
+        "differences",
+        "exit",
+        "error",
+        "behavior",
+        "fallbacks",
+        "late_days",
+        "late_penalty",
+    ])?;
+
+    for report in reports {
+        let error = report.error.as_deref().unwrap_or_default();
+        let fallbacks = report
+            .fallbacks
+            .iter()
+            .map(|fallback| fallback.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let (late_days, late_penalty) = match report.late {
+            Some(late) => (late.days.to_string(), late.penalty.to_string()),
+            None => (String::new(), String::new()),
+        };
+
+        if report.tests.is_empty() {
+            writer.write_record([
+                report.student.as_str(),
+                "",
+                "",
+                &report.score.earned.to_string(),
+                &report.score.total.to_string(),
+                "",
+                "",
+                error,
+                "",
+                &fallbacks,
+                &late_days,
+                &late_penalty,
+            ])?;
+        }
+
+        for test in &report.tests {
+            let differences = test
+                .differences
+                .iter()
+                .map(|line| match line {
+                    Line::Add(added) => format!("+ {}", added),
+                    Line::Rem(removed) => format!("- {}", removed),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            writer.write_record([
+                report.student.as_str(),
+                &test.name,
+                match test.status {
+                    Status::Pass => "pass",
+                    Status::Fail => "fail",
+                    Status::Error => "error",
+                },
+                &test.score.earned.to_string(),
+                &test.score.total.to_string(),
+                &differences,
+                &test.exit.map(|exit| exit.to_string()).unwrap_or_default(),
+                test.error.as_deref().unwrap_or(error),
+                &test
+                    .behavior
+                    .as_ref()
+                    .map(Behavior::to_string)
+                    .unwrap_or_default(),
+                &fallbacks,
+                &late_days,
+                &late_penalty,
+            ])?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
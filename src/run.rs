@@ -0,0 +1,238 @@
+use std::fmt;
+use std::io;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::os::unix::process::CommandExt as _;
+use std::os::unix::process::ExitStatusExt as _;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::sandbox::Limits;
+
+/// Most output kept from each of stdout and stderr, in bytes. A student
+/// binary that prints more is killed, rather than exhausting memory.
+const LIMIT: u64 = 4 << 20;
+
+/// Options shared by every grader.
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    /// Wall-clock limit for each run of a student binary.
+    pub timeout: Duration,
+
+    /// Isolate `make` and student binaries with these limits, if set.
+    pub sandbox: Option<Limits>,
+}
+
+/// How a student binary terminated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum Exit {
+    Success,
+
+    /// Killed after exceeding the timeout (in seconds).
+    Timeout(u64),
+
+    /// Killed after printing more than the output limit (in MiB).
+    OutputLimit(u64),
+
+    /// SIGSEGV
+    Segfault,
+
+    /// SIGABRT, e.g. from a failed assertion
+    Abort,
+
+    /// Killed by some other signal.
+    Signal(i32),
+
+    /// Exited normally with a non-zero code.
+    Code(i32),
+}
+
+impl Exit {
+    fn new(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(0), _) => Exit::Success,
+            (Some(code), _) => Exit::Code(code),
+            (None, Some(11)) => Exit::Segfault,
+            (None, Some(6)) => Exit::Abort,
+            (None, Some(signal)) => Exit::Signal(signal),
+            (None, None) => unreachable!("[INTERNAL ERROR]: exit status without code or signal"),
+        }
+    }
+
+    /// Killed by the grader, so any output is incomplete.
+    pub(crate) fn killed(&self) -> bool {
+        matches!(self, Exit::Timeout(_) | Exit::OutputLimit(_))
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exit::Success => write!(fmt, "exited successfully"),
+            Exit::Timeout(seconds) => write!(fmt, "timed out after {}s", seconds),
+            Exit::OutputLimit(mebibytes) => {
+                write!(fmt, "killed after printing more than {}MiB", mebibytes)
+            }
+            Exit::Segfault => write!(fmt, "SIGSEGV (segmentation fault)"),
+            Exit::Abort => write!(fmt, "SIGABRT (failed assertion)"),
+            Exit::Signal(8) => write!(fmt, "SIGFPE (floating point exception)"),
+            Exit::Signal(24) => write!(fmt, "SIGXCPU (CPU time limit exceeded)"),
+            Exit::Signal(25) => write!(fmt, "SIGXFSZ (file size limit exceeded)"),
+            Exit::Signal(signal) => write!(fmt, "killed by signal {}", signal),
+            Exit::Code(code) => write!(fmt, "exited with code {}", code),
+        }
+    }
+}
+
+pub(crate) struct Output {
+    pub(crate) stdout: Vec<u8>,
+
+    /// Kept with the output rather than inherited, so messages from
+    /// concurrently graded workspaces don't interleave.
+    pub(crate) stderr: Vec<u8>,
+    pub(crate) exit: Exit,
+}
+
+impl Output {
+    /// Whatever was written to stderr, if anything.
+    pub(crate) fn stderr(&self) -> Option<String> {
+        match self.stderr.is_empty() {
+            true => None,
+            false => Some(String::from_utf8_lossy(&self.stderr).into_owned()),
+        }
+    }
+}
+
+/// Run `command` with `input` on stdin, killing it if it exceeds the timeout
+/// or the output limit.
+pub(crate) fn run(mut command: Command, input: &[u8], options: &Options) -> anyhow::Result<Output> {
+    // Run in a new process group, so any processes the student spawns are
+    // killed along with it
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain stdout and stderr concurrently so the child can't block on a full pipe
+    let flooded = Arc::new(AtomicBool::new(false));
+    let reader = drain(child.stdout.take().unwrap(), Arc::clone(&flooded));
+    let errors = drain(child.stderr.take().unwrap(), Arc::clone(&flooded));
+
+    // Write stdin concurrently too, so a child that never reads its input
+    // can't block the grader past the timeout
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+
+    let start = Instant::now();
+    let exit = loop {
+        if let Some(status) = child.try_wait()? {
+            break Exit::new(status);
+        }
+
+        let flooded = flooded.load(Ordering::Relaxed);
+        if flooded || start.elapsed() >= options.timeout {
+            // SAFETY: `kill` has no memory safety requirements
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            child.wait()?;
+            break match flooded {
+                true => Exit::OutputLimit(LIMIT >> 20),
+                false => Exit::Timeout(options.timeout.as_secs()),
+            };
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    // Students may exit (or crash) before reading all of their input. After a
+    // timeout the writer may still be blocked on a pipe that something else
+    // holds open, so detach it rather than wait.
+    if !exit.killed() {
+        match writer
+            .join()
+            .expect("[INTERNAL ERROR]: stdin writer panicked")
+        {
+            Err(error) if error.kind() == io::ErrorKind::BrokenPipe => (),
+            result => result?,
+        }
+    }
+
+    let stdout = reader
+        .join()
+        .expect("[INTERNAL ERROR]: stdout reader panicked")?;
+    let stderr = errors
+        .join()
+        .expect("[INTERNAL ERROR]: stderr reader panicked")?;
+
+    // The child may have exited on its own before the limit was noticed
+    let exit = match flooded.load(Ordering::Relaxed) {
+        true => Exit::OutputLimit(LIMIT >> 20),
+        false => exit,
+    };
+
+    Ok(Output {
+        stdout,
+        stderr,
+        exit,
+    })
+}
+
+/// Read `pipe` to the end, keeping at most `LIMIT` bytes, and raising
+/// `flooded` if there was more.
+fn drain<R: io::Read + Send + 'static>(
+    mut pipe: R,
+    flooded: Arc<AtomicBool>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        pipe.by_ref().take(LIMIT + 1).read_to_end(&mut buffer)?;
+
+        if buffer.len() as u64 > LIMIT {
+            buffer.truncate(LIMIT as usize);
+            flooded.store(true, Ordering::Relaxed);
+
+            // Keep the pipe from filling up until the child is killed
+            io::copy(&mut pipe, &mut io::sink())?;
+        }
+
+        Ok(buffer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::run;
+    use super::Exit;
+    use super::Options;
+    use super::LIMIT;
+
+    #[test]
+    fn output_limit() {
+        let options = Options {
+            timeout: Duration::from_secs(30),
+            sandbox: None,
+        };
+
+        let output = run(Command::new("yes"), &[], &options).unwrap();
+        assert_eq!(output.exit, Exit::OutputLimit(LIMIT >> 20));
+        assert_eq!(output.stdout.len() as u64, LIMIT);
+
+        let output = run(Command::new("echo"), &[], &options).unwrap();
+        assert_eq!(output.exit, Exit::Success);
+    }
+}
@@ -0,0 +1,334 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::io::FromRawFd as _;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use tempdir::TempDir;
+
+use crate::run::Options;
+
+/// Resource limits for each sandboxed process (and each of its children).
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// CPU time, in seconds.
+    pub cpu: u64,
+
+    /// Address space, in bytes.
+    pub memory: u64,
+
+    /// Largest file that may be written, in bytes.
+    pub file_size: u64,
+
+    /// Processes owned by the grading user. Note that the kernel counts every
+    /// process of the user, not just those spawned by the student.
+    pub processes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            cpu: 30,
+            memory: 1 << 30,
+            file_size: 64 << 20,
+            processes: 512,
+        }
+    }
+}
+
+/// Spawns `make` and student binaries inside a workspace, optionally isolated
+/// according to [`Options::sandbox`].
+pub(crate) struct Sandbox {
+    workspace: PathBuf,
+    isolation: Option<Isolation>,
+
+    /// Owns `workspace` if this is a scratch copy, deleting it on drop.
+    _scratch: Option<TempDir>,
+}
+
+/// Private temp directory and Landlock ruleset shared by every process
+/// spawned for one workspace.
+struct Isolation {
+    limits: Limits,
+    temp: TempDir,
+    ruleset: OwnedFd,
+}
+
+impl Sandbox {
+    pub(crate) fn new(workspace: PathBuf, options: &Options) -> anyhow::Result<Self> {
+        let isolation = options
+            .sandbox
+            .map(|limits| Isolation::new(limits, &workspace))
+            .transpose()?;
+
+        Ok(Sandbox {
+            workspace,
+            isolation,
+            _scratch: None,
+        })
+    }
+
+    /// Copy the files directly in the workspace, except those `skip` rejects,
+    /// to a temporary directory, and sandbox that instead.
+    pub(crate) fn scratch<F>(&self, skip: F) -> anyhow::Result<Self>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let scratch = TempDir::new("cs375-autograder-scratch")
+            .context("Could not create scratch directory")?;
+
+        for entry in fs::read_dir(&self.workspace)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !entry.file_type()?.is_file() || skip(&name.to_string_lossy()) {
+                continue;
+            }
+
+            fs::copy(entry.path(), scratch.path().join(&name)).with_context(|| {
+                anyhow!(
+                    "Could not copy {} to scratch directory",
+                    entry.path().display()
+                )
+            })?;
+        }
+
+        let workspace = scratch.path().to_path_buf();
+        let isolation = self
+            .isolation
+            .as_ref()
+            .map(|isolation| Isolation::new(isolation.limits, &workspace))
+            .transpose()?;
+
+        Ok(Sandbox {
+            workspace,
+            isolation,
+            _scratch: Some(scratch),
+        })
+    }
+
+    pub(crate) fn workspace(&self) -> &Path {
+        &self.workspace
+    }
+
+    /// Build a command that runs `program` in the workspace.
+    pub(crate) fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        let mut command = Command::new(program);
+        command.current_dir(&self.workspace);
+
+        if let Some(isolation) = &self.isolation {
+            command
+                .env("TMPDIR", isolation.temp.path())
+                .env("HOME", isolation.temp.path());
+
+            let limits = isolation.limits;
+            let ruleset = isolation.ruleset.as_raw_fd();
+
+            // SAFETY: the closure only makes async-signal-safe system calls,
+            // and `ruleset` outlives the command because `self` does
+            unsafe {
+                command.pre_exec(move || isolate(limits, ruleset));
+            }
+        }
+
+        command
+    }
+}
+
+impl Isolation {
+    fn new(limits: Limits, workspace: &Path) -> anyhow::Result<Self> {
+        let temp =
+            TempDir::new("cs375-autograder").context("Could not create private temp directory")?;
+        let ruleset = landlock::ruleset(&[workspace, temp.path()])
+            .context("Could not restrict file system access")?;
+        Ok(Isolation {
+            limits,
+            temp,
+            ruleset,
+        })
+    }
+}
+
+/// Runs in the child between `fork` and `exec`.
+fn isolate(limits: Limits, ruleset: RawFd) -> io::Result<()> {
+    for (resource, limit) in [
+        (libc::RLIMIT_CPU, limits.cpu),
+        (libc::RLIMIT_AS, limits.memory),
+        (libc::RLIMIT_FSIZE, limits.file_size),
+        (libc::RLIMIT_NPROC, limits.processes),
+    ] {
+        let limit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+
+        // SAFETY: `limit` is a valid `rlimit`
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Required before an unprivileged process can restrict itself
+    // SAFETY: `prctl` has no memory safety requirements
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    landlock::restrict_self(ruleset)
+}
+
+/// Minimal bindings for the Landlock LSM, which `libc` doesn't cover yet.
+/// See `Documentation/userspace-api/landlock.rst` in the kernel source.
+mod landlock {
+    use super::*;
+
+    const SYS_CREATE_RULESET: libc::c_long = 444;
+    const SYS_ADD_RULE: libc::c_long = 445;
+    const SYS_RESTRICT_SELF: libc::c_long = 446;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Build a ruleset that denies every kind of write, except beneath
+    /// `writable` and to `/dev/null`. Reads and execution are unrestricted.
+    pub(super) fn ruleset(writable: &[&Path]) -> anyhow::Result<OwnedFd> {
+        // SAFETY: querying the ABI version takes no attribute
+        let abi = unsafe {
+            libc::syscall(
+                SYS_CREATE_RULESET,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+
+        if abi < 1 {
+            return Err(anyhow!(
+                "Landlock is not supported by this kernel: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        // Newer ABIs must handle these explicitly, or they're denied
+        // (`REFER`) or unrestricted (`TRUNCATE`)
+        let mut handled = ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+
+        // SAFETY: `attr` is a valid ruleset attribute of the given size
+        let fd = unsafe {
+            libc::syscall(
+                SYS_CREATE_RULESET,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // SAFETY: `fd` was just returned by the kernel and is owned by no one else
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        for directory in writable {
+            add_rule(&ruleset, directory, handled)?;
+        }
+
+        // Only file (not directory) rights may be granted on a file
+        add_rule(
+            &ruleset,
+            Path::new("/dev/null"),
+            handled & (ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE),
+        )?;
+
+        Ok(ruleset)
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, allowed_access: u64) -> anyhow::Result<()> {
+        let parent = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+            .with_context(|| anyhow!("Could not open {}", path.display()))?;
+
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: parent.as_raw_fd(),
+        };
+
+        // SAFETY: `attr` is a valid path-beneath attribute
+        if unsafe {
+            libc::syscall(
+                SYS_ADD_RULE,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error())
+                .with_context(|| anyhow!("Could not allow writes to {}", path.display()));
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn restrict_self(ruleset: RawFd) -> io::Result<()> {
+        // SAFETY: `restrict_self` has no memory safety requirements
+        match unsafe { libc::syscall(SYS_RESTRICT_SELF, ruleset, 0) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
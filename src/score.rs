@@ -0,0 +1,102 @@
+use std::fmt;
+use std::ops::AddAssign;
+
+use serde::Serialize;
+
+/// A kind of mismatch between student output and the sample.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Mismatch {
+    /// An incorrect, missing, or extra symbol in the symbol table.
+    Symbol,
+
+    /// An incorrect line of output (or subtree of the parse tree).
+    Line,
+
+    /// The student binary was killed after exceeding the timeout or the
+    /// output limit.
+    Killed,
+}
+
+/// Points awarded for a single test, following `rubrics/`.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Rubric {
+    pub(crate) points: u32,
+
+    /// Points deducted for each kind of mismatch. Any mismatch without
+    /// a deduction fails the whole test.
+    pub(crate) deductions: &'static [(Mismatch, u32)],
+
+    /// Points the score is scaled to (rounding to the nearest point), when a
+    /// rubric is reused at a different weight.
+    pub(crate) scale: Option<u32>,
+}
+
+impl Rubric {
+    /// All-or-nothing test.
+    pub(crate) const fn all(points: u32) -> Self {
+        Rubric {
+            points,
+            deductions: &[],
+            scale: None,
+        }
+    }
+
+    /// The same deductions, with the score scaled to `points`.
+    pub(crate) const fn scaled(self, points: u32) -> Self {
+        Rubric {
+            scale: Some(points),
+            ..self
+        }
+    }
+
+    /// Points the test is worth.
+    pub(crate) fn total(&self) -> u32 {
+        self.scale.unwrap_or(self.points)
+    }
+
+    pub(crate) fn score(&self, mismatches: &[Mismatch]) -> Score {
+        let mut deducted = 0;
+
+        for mismatch in mismatches {
+            match self.deductions.iter().find(|(kind, _)| kind == mismatch) {
+                Some((_, deduction)) => deducted += deduction,
+                None => {
+                    deducted = self.points;
+                    break;
+                }
+            }
+        }
+
+        let earned = self.points.saturating_sub(deducted);
+
+        match self.scale {
+            None => Score {
+                earned,
+                total: self.points,
+            },
+            Some(scale) => Score {
+                earned: (earned * scale + self.points / 2) / self.points,
+                total: scale,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct Score {
+    pub earned: u32,
+    pub total: u32,
+}
+
+impl AddAssign for Score {
+    fn add_assign(&mut self, other: Self) {
+        self.earned += other.earned;
+        self.total += other.total;
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}/{}", self.earned, self.total)
+    }
+}